@@ -0,0 +1,796 @@
+use crate::nes::BusDevice;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub(crate) const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Periods in CPU cycles (NTSC)
+const NOISE_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const DMC_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// CPU cycles taken by a DMC sample fetch
+pub const DMC_STALL_CYCLES: u32 = 4;
+
+// Shared with the expansion audio on some mappers
+#[derive(Debug, Default)]
+pub(crate) struct Envelope {
+    pub(crate) start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub(crate) fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0f;
+    }
+
+    pub(crate) fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        }
+        else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            }
+            else if self.looping {
+                self.decay = 15;
+            }
+        }
+        else {
+            self.divider -= 1;
+        }
+    }
+
+    pub(crate) fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+
+    pub(crate) fn save_state(&self, state: StateWriter) -> StateWriter {
+        state
+            .bool(self.start)
+            .bool(self.looping)
+            .bool(self.constant)
+            .u8(self.volume)
+            .u8(self.divider)
+            .u8(self.decay)
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.start = r.bool()?;
+        self.looping = r.bool()?;
+        self.constant = r.bool()?;
+        self.volume = r.u8()? & 0x0f;
+        self.divider = r.u8()? & 0x0f;
+        self.decay = r.u8()? & 0x0f;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LengthCounter {
+    enabled: bool,
+    pub(crate) halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub(crate) fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1f) as usize];
+        }
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub(crate) fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub(crate) fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub(crate) fn save_state(&self, state: StateWriter) -> StateWriter {
+        state
+            .bool(self.enabled)
+            .bool(self.halt)
+            .u8(self.counter)
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.enabled = r.bool()?;
+        self.halt = r.bool()?;
+        self.counter = r.u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pulse {
+    // Pulse 1 negates with one's complement, pulse 2 with two's complement
+    ones_complement: bool,
+    duty: u8,
+    duty_pos: u8,
+    timer: u16,
+    timer_period: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Self {
+            ones_complement,
+            ..Default::default()
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | (((data & 0x07) as u16) << 8);
+                self.length.load(data >> 3);
+                self.duty_pos = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = if self.ones_complement { change + 1 } else { change };
+            self.timer_period.saturating_sub(change)
+        }
+        else {
+            self.timer_period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7ff
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        }
+        else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.muted() || DUTY_TABLE[self.duty as usize][self.duty_pos as usize] == 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Triangle {
+    timer: u16,
+    timer_period: u16,
+    length: LengthCounter,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    sequence_pos: u8,
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x80 != 0;
+                self.linear_reload_value = data & 0x7f;
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | (((data & 0x07) as u16) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.sequence_pos = (self.sequence_pos + 1) & 0x1f;
+            }
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        }
+        else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        // The control flag doubles as the length counter halt
+        if !self.length.halt {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence_pos as usize]
+    }
+}
+
+#[derive(Debug)]
+struct Noise {
+    mode: bool,
+    timer: u16,
+    timer_period: u16,
+    shift: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            mode: false,
+            timer: 0,
+            timer_period: NOISE_TABLE[0],
+            shift: 1,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.mode = data & 0x80 != 0;
+                self.timer_period = NOISE_TABLE[(data & 0x0f) as usize];
+            }
+            _ => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle, the period table is in CPU cycles
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+#[derive(Debug)]
+struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    timer: u16,
+    timer_period: u16,
+    output: u8,
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            timer: 0,
+            timer_period: DMC_TABLE[0],
+            output: 0,
+            sample_addr: 0xc000,
+            sample_length: 1,
+            current_addr: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.looping = data & 0x40 != 0;
+                self.timer_period = DMC_TABLE[(data & 0x0f) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.output = data & 0x7f,
+            2 => self.sample_addr = 0xc000 + ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) + 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        }
+        else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Clocked every CPU cycle, the rate table is in CPU cycles
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.output <= 125 {
+                    self.output += 2;
+                }
+            }
+            else if self.output >= 2 {
+                self.output -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // Address the memory reader wants fetched, if the buffer is empty
+    fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        }
+        else {
+            None
+        }
+    }
+
+    fn dma_complete(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_addr = if self.current_addr == 0xffff { 0x8000 } else { self.current_addr + 1 };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            }
+            else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    cycle: u64,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            cycle: 0,
+        }
+    }
+
+    // Clocked once per CPU cycle
+    pub fn clock(&mut self) {
+        self.clock_frame_counter();
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.cycle += 1;
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+
+        match (self.five_step, self.frame_cycle) {
+            (_, 7457) | (_, 22371) => self.clock_quarter_frame(),
+            (_, 14913) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (false, 29829) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+            }
+            (false, 29830) => self.frame_cycle = 0,
+            (true, 37281) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (true, 37282) => self.frame_cycle = 0,
+            _ => {}
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // The DMC memory reader asks for a byte when its buffer empties,
+    // the caller does the read and stalls the CPU
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_dma_complete(&mut self, data: u8) {
+        self.dmc.dma_complete(data);
+    }
+
+    // Mix the channels with the non-linear DAC approximations
+    pub fn sample(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        }
+        else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let triangle = self.triangle.output() as f32;
+        let noise = self.noise.output() as f32;
+        let dmc = self.dmc.output as f32;
+        let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        }
+        else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+
+        pulse_out + tnd_out
+    }
+}
+
+impl BusDevice for Apu {
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        if addr != 0x4015 {
+            return None;
+        }
+
+        let mut status = 0;
+        status |= self.pulse1.length.active() as u8;
+        status |= (self.pulse2.length.active() as u8) << 1;
+        status |= (self.triangle.length.active() as u8) << 2;
+        status |= (self.noise.length.active() as u8) << 3;
+        status |= ((self.dmc.bytes_remaining > 0) as u8) << 4;
+        status |= (self.frame_irq as u8) << 6;
+        status |= (self.dmc.irq as u8) << 7;
+
+        self.frame_irq = false;
+        Some(status)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr & 0x03, data),
+            0x4004..=0x4007 => self.pulse2.write(addr & 0x03, data),
+            0x4008..=0x400b => self.triangle.write(addr & 0x03, data),
+            0x400c..=0x400f => self.noise.write(addr & 0x03, data),
+            0x4010..=0x4013 => self.dmc.write(addr & 0x03, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+                self.dmc.irq = false;
+            }
+            0x4017 => {
+                self.five_step = data & 0x80 != 0;
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+
+    #[test]
+    fn four_step_sequence_raises_the_frame_irq() {
+        let mut apu = Apu::new();
+        run(&mut apu, 29828);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+
+        // Reading $4015 reports and acknowledges it
+        assert_eq!(apu.read(0x4015).unwrap() & 0x40, 0x40);
+        assert!(!apu.irq());
+        assert_eq!(apu.read(0x4015).unwrap() & 0x40, 0x00);
+    }
+
+    #[test]
+    fn frame_irq_inhibit() {
+        let mut apu = Apu::new();
+        run(&mut apu, 29829);
+        assert!(apu.irq());
+
+        // Setting the inhibit flag clears a pending IRQ and stops new ones
+        apu.write(0x4017, 0x40);
+        assert!(!apu.irq());
+        run(&mut apu, 29830 * 2);
+        assert!(!apu.irq());
+
+        // Clearing it lets the next sequence raise one again
+        apu.write(0x4017, 0x00);
+        run(&mut apu, 29829);
+        assert!(apu.irq());
+    }
+
+    #[test]
+    fn five_step_sequence_has_no_frame_irq() {
+        let mut apu = Apu::new();
+        apu.write(0x4017, 0x80);
+        run(&mut apu, 37282 * 2);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn length_counters_load_only_while_enabled() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x08);
+        apu.write(0x4007, 0x08);
+        assert_eq!(apu.read(0x4015).unwrap() & 0x0f, 0x01);
+        assert_eq!(apu.pulse1.length.counter, 254);
+
+        // Disabling a channel zeroes its counter straight away
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.read(0x4015).unwrap() & 0x0f, 0x00);
+    }
+
+    #[test]
+    fn length_counters_count_half_frames_unless_halted() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x03);
+        // Length index 3 is 2 half frames, pulse 2 has the halt flag set
+        apu.write(0x4004, 0x20);
+        apu.write(0x4003, 0x18);
+        apu.write(0x4007, 0x18);
+
+        run(&mut apu, 14913);
+        assert_eq!(apu.read(0x4015).unwrap() & 0x03, 0x03);
+        run(&mut apu, 29829 - 14913);
+        assert_eq!(apu.read(0x4015).unwrap() & 0x03, 0x02);
+
+        // Clearing halt lets it count down again
+        apu.write(0x4004, 0x00);
+        run(&mut apu, 14914 + 29830);
+        assert_eq!(apu.read(0x4015).unwrap() & 0x03, 0x00);
+    }
+
+    // Pulse 1 with a constant volume of 15 and the 50% duty
+    fn pulse_with_period(period: u16, sweep: u8) -> Apu {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x01);
+        apu.write(0x4000, 0xbf);
+        apu.write(0x4001, sweep);
+        apu.write(0x4002, period as u8);
+        apu.write(0x4003, 0x08 | (period >> 8) as u8);
+        apu
+    }
+
+    fn pulse_sounds(apu: &mut Apu) -> bool {
+        (0..200).any(|_| {
+            apu.clock();
+            apu.pulse1.output() != 0
+        })
+    }
+
+    #[test]
+    fn sweep_mutes_periods_below_8() {
+        assert!(!pulse_sounds(&mut pulse_with_period(7, 0x00)));
+        assert!(pulse_sounds(&mut pulse_with_period(8, 0x00)));
+    }
+
+    #[test]
+    fn sweep_mutes_targets_past_7ff() {
+        // $600 + ($600 >> 1) overflows, even with the sweep unit disabled
+        let mut apu = pulse_with_period(0x600, 0x01);
+        assert!(!pulse_sounds(&mut apu));
+        apu.write(0x4001, 0x02);
+        assert!(pulse_sounds(&mut apu));
+    }
+
+    #[test]
+    fn muted_pulse_keeps_its_period() {
+        // Sweep enabled with a divider period of 0 and a shift of 1, but the target is out of range
+        let mut apu = pulse_with_period(0x600, 0x81);
+        run(&mut apu, 29830);
+        assert_eq!(apu.pulse1.timer_period, 0x600);
+
+        let mut apu = pulse_with_period(0x200, 0x81);
+        run(&mut apu, 14913);
+        assert_eq!(apu.pulse1.timer_period, 0x300);
+    }
+}
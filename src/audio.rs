@@ -0,0 +1,210 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+
+// NTSC CPU clock, the rate the APU produces samples at
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+
+// Second order low pass section (RBJ cookbook), run at the input rate
+#[derive(Debug)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    fn low_pass(sample_rate: f64, cutoff: f64, q: f64) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+
+        Self {
+            b0: (1.0 - cos) / 2.0 / a0,
+            b1: (1.0 - cos) / a0,
+            b2: (1.0 - cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+// Downsamples the APU output from the CPU clock rate to an audio rate.
+// A 4th order Butterworth low pass band limits the signal before each
+// output period is box averaged, then a high pass removes the DC offset.
+#[derive(Debug)]
+pub struct Resampler {
+    step: f64,
+    phase: f64,
+    sum: f64,
+    count: u32,
+    filters: [Biquad; 2],
+    dc_in: f64,
+    dc_out: f64,
+    dc_coefficient: f64,
+    output: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+        let cutoff = (rate * 0.45).min(20_000.0);
+
+        Self {
+            step: rate / CPU_CLOCK_RATE,
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+            filters: [
+                Biquad::low_pass(CPU_CLOCK_RATE, cutoff, 0.541_196_1),
+                Biquad::low_pass(CPU_CLOCK_RATE, cutoff, 1.306_563),
+            ],
+            dc_in: 0.0,
+            dc_out: 0.0,
+            // ~20Hz high pass
+            dc_coefficient: (-2.0 * std::f64::consts::PI * 20.0 / rate).exp(),
+            output: vec![],
+        }
+    }
+
+    // Feed one sample at the CPU clock rate
+    pub fn push(&mut self, sample: f32) {
+        let mut x = sample as f64;
+        for filter in self.filters.iter_mut() {
+            x = filter.process(x);
+        }
+        self.sum += x;
+        self.count += 1;
+
+        self.phase += self.step;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+
+            let average = self.sum / self.count as f64;
+            self.sum = 0.0;
+            self.count = 0;
+
+            let y = average - self.dc_in + self.dc_coefficient * self.dc_out;
+            self.dc_in = average;
+            self.dc_out = y;
+            self.output.push(y as f32);
+        }
+    }
+
+    pub fn drain(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+// Write mono 16 bit PCM
+pub fn write_wav(filename: &str, sample_rate: u32, samples: &[f32]) -> std::io::Result<()> {
+    let mut f = BufWriter::new(File::create(filename)?);
+    let data_size = samples.len() as u32 * 2;
+
+    f.write_all(b"RIFF")?;
+    f.write_all(&(36 + data_size).to_le_bytes())?;
+    f.write_all(b"WAVE")?;
+
+    f.write_all(b"fmt ")?;
+    f.write_all(&16u32.to_le_bytes())?;
+    f.write_all(&1u16.to_le_bytes())?; // PCM
+    f.write_all(&1u16.to_le_bytes())?; // Mono
+    f.write_all(&sample_rate.to_le_bytes())?;
+    f.write_all(&(sample_rate * 2).to_le_bytes())?;
+    f.write_all(&2u16.to_le_bytes())?;
+    f.write_all(&16u16.to_le_bytes())?;
+
+    f.write_all(b"data")?;
+    f.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        f.write_all(&value.to_le_bytes())?;
+    }
+
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One second of a sine at `frequency`, fed at the CPU clock rate
+    fn resample_sine(frequency: f64, sample_rate: u32) -> Vec<f32> {
+        let mut resampler = Resampler::new(sample_rate);
+        for n in 0..CPU_CLOCK_RATE as usize {
+            let t = n as f64 / CPU_CLOCK_RATE;
+            resampler.push((2.0 * std::f64::consts::PI * frequency * t).sin() as f32 * 0.5);
+        }
+        resampler.drain()
+    }
+
+    // Peak level once the filters have settled
+    fn peak(samples: &[f32]) -> f32 {
+        samples[samples.len() / 2..].iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn output_rate() {
+        for rate in [44100, 48000] {
+            let mut resampler = Resampler::new(rate);
+            for _ in 0..CPU_CLOCK_RATE as usize {
+                resampler.push(0.0);
+            }
+            let produced = resampler.drain().len() as i64;
+            assert!((produced - rate as i64).abs() <= 1, "{} samples at {}Hz", produced, rate);
+        }
+    }
+
+    #[test]
+    fn drain_empties_output() {
+        let mut resampler = Resampler::new(44100);
+        for _ in 0..1000 {
+            resampler.push(0.5);
+        }
+        assert!(!resampler.drain().is_empty());
+        assert!(resampler.drain().is_empty());
+    }
+
+    #[test]
+    fn passes_audible_tones() {
+        let level = peak(&resample_sine(1000.0, 44100));
+        assert!((0.45..0.55).contains(&level), "1kHz peak {}", level);
+    }
+
+    #[test]
+    fn band_limits_above_nyquist() {
+        // Without the low pass a 30kHz tone would alias down to 14.1kHz
+        let level = peak(&resample_sine(30_000.0, 44100));
+        assert!(level < 0.05, "30kHz peak {}", level);
+    }
+
+    #[test]
+    fn removes_dc_offset() {
+        let mut resampler = Resampler::new(44100);
+        for _ in 0..CPU_CLOCK_RATE as usize {
+            resampler.push(0.8);
+        }
+        let output = resampler.drain();
+        assert!(output.last().unwrap().abs() < 0.01);
+    }
+}
@@ -1,9 +1,99 @@
 use crate::nes::BusDevice;
 use std::fs::File;
 use std::io::Read;
-use std::cmp::min;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
 use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
 use crate::mappers::mapper0::Mapper0;
+use crate::mappers::mapper1::Mapper1;
+use crate::mappers::mapper2::Mapper2;
+use crate::mappers::mapper3::Mapper3;
+use crate::mappers::mapper4::Mapper4;
+use crate::mappers::mapper5::Mapper5;
+use crate::mappers::mapper7::Mapper7;
+use crate::mappers::mapper9::Mapper9;
+use crate::mappers::mapper10::Mapper10;
+use crate::mappers::mapper19::Mapper19;
+use crate::mappers::mapper21::Mapper21;
+use crate::mappers::mapper24::Mapper24;
+use crate::mappers::mapper69::Mapper69;
+use crate::mappers::mapper85::Mapper85;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+impl Mirroring {
+    // Map one of the four logical nametables at $2000-$2FFF onto a 1KB page of
+    // CIRAM (four screen boards supply pages 2 and 3 themselves)
+    pub fn ciram_offset(self, addr: u16) -> usize {
+        let table = (addr >> 10) & 0x03;
+        let page = match self {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        ((page << 10) | (addr & 0x03ff)) as usize
+    }
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(std::io::Error),
+    BadMagic,
+    TruncatedHeader,
+    TruncatedTrainer { expected: usize, found: usize },
+    TruncatedPrg { expected: usize, found: usize },
+    TruncatedChr { expected: usize, found: usize },
+    UnsupportedMapper(u16),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "io error: {}", e),
+            CartridgeError::BadMagic => write!(f, "not an iNES image (bad magic)"),
+            CartridgeError::TruncatedHeader => write!(f, "file too short for an iNES header"),
+            CartridgeError::TruncatedTrainer { expected, found } => {
+                write!(f, "truncated trainer: expected {} bytes, found {}", expected, found)
+            }
+            CartridgeError::TruncatedPrg { expected, found } => {
+                write!(f, "truncated PRG ROM: expected {} bytes, found {}", expected, found)
+            }
+            CartridgeError::TruncatedChr { expected, found } => {
+                write!(f, "truncated CHR ROM: expected {} bytes, found {}", expected, found)
+            }
+            CartridgeError::UnsupportedMapper(id) => write!(f, "unsupported mapper id: {}", id),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CartridgeError {
+    fn from(e: std::io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
 
 #[derive(Debug)]
 pub struct Cartridge {
@@ -11,119 +101,634 @@ pub struct Cartridge {
     mapper: Box<dyn Mapper>,
     prg_mem: Vec<u8>,
     chr_mem: Vec<u8>,
+    // Boards without CHR ROM carry writable CHR RAM instead
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    // Where battery backed PRG-RAM is persisted
+    save_path: Option<PathBuf>,
+}
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderFormat {
+    INes,
+    Nes20,
 }
 
-#[derive(Debug)]
-struct Header {
-    name: String,
-    prg_rom_chunks: u8,
-    chr_rom_chunks: u8,
-    mapper1: u8,
-    mapper2: u8,
-    prg_ram_size: u8,
-    tv_system1: u8,
-    tv_system2: u8,
-    //Derived
-    mapper_id: u8,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    // NES 2.0 extended console type from byte 13
+    Extended(u8),
+}
+
+// Parsed iNES / NES 2.0 header, sizes are in bytes
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub format: HeaderFormat,
+    pub mapper_id: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub timing: Timing,
+    pub console: ConsoleType,
+    pub expansion_device: u8,
 }
 
 impl Header {
-    fn new(file: &Vec<u8>) -> Self {
-        println!("{:?}", file[0..4].to_vec());
-        Self {
-            name: String::from_utf8(file[0..4].to_vec()).unwrap(),
-            prg_rom_chunks: file[4],
-            chr_rom_chunks: file[5],
-            mapper1: file[6],
-            mapper2: file[7],
-            prg_ram_size: file[8],
-            tv_system1: file[9],
-            tv_system2: file[10],
-            mapper_id: ((file[7] >> 4) << 4) | (file[6] >> 4)
+    pub fn new(file: &[u8]) -> Result<Self, CartridgeError> {
+        if file.len() < 4 || &file[0..4] != b"NES\x1a" {
+            return Err(CartridgeError::BadMagic);
+        }
+        if file.len() < 16 {
+            return Err(CartridgeError::TruncatedHeader);
+        }
+
+        let mirroring = if file[6] & 0x08 != 0 {
+            Mirroring::FourScreen
+        }
+        else if file[6] & 0x01 != 0 {
+            Mirroring::Vertical
+        }
+        else {
+            Mirroring::Horizontal
+        };
+
+        let console = match file[7] & 0x03 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(file[13] & 0x0f),
+        };
+
+        if file[7] & 0x0c == 0x08 {
+            return Ok(Self {
+                format: HeaderFormat::Nes20,
+                mapper_id: (((file[8] & 0x0f) as u16) << 8) | (file[7] & 0xf0) as u16 | (file[6] >> 4) as u16,
+                submapper: file[8] >> 4,
+                prg_rom_size: Self::rom_size(file[4], file[9] & 0x0f, 0x4000),
+                chr_rom_size: Self::rom_size(file[5], file[9] >> 4, 0x2000),
+                prg_ram_size: Self::ram_size(file[10] & 0x0f),
+                prg_nvram_size: Self::ram_size(file[10] >> 4),
+                chr_ram_size: Self::ram_size(file[11] & 0x0f),
+                chr_nvram_size: Self::ram_size(file[11] >> 4),
+                mirroring,
+                battery: file[6] & 0x02 != 0,
+                trainer: file[6] & 0x04 != 0,
+                timing: match file[12] & 0x03 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                },
+                console,
+                expansion_device: file[15] & 0x3f,
+            });
+        }
+
+        // Old dumps with junk (e.g. "DiskDude!") in bytes 12-15 also have junk in byte 7
+        let mapper_hi = if file[12..16].iter().all(|b| *b == 0) { file[7] & 0xf0 } else { 0 };
+
+        Ok(Self {
+            format: HeaderFormat::INes,
+            mapper_id: (mapper_hi | (file[6] >> 4)) as u16,
+            submapper: 0,
+            prg_rom_size: file[4] as usize * 0x4000,
+            chr_rom_size: file[5] as usize * 0x2000,
+            // 0 means 8KB for compatibility
+            prg_ram_size: file[8].max(1) as usize * 0x2000,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            mirroring,
+            battery: file[6] & 0x02 != 0,
+            trainer: file[6] & 0x04 != 0,
+            timing: if file[9] & 0x01 != 0 { Timing::Pal } else { Timing::Ntsc },
+            console,
+            expansion_device: 0,
+        })
+    }
+
+    // NES 2.0 ROM sizes are either a 12 bit unit count or, with an MSB nibble
+    // of $F, an exponent-multiplier pair EEEEEEMM giving 2^E * (MM*2+1)
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+        if msb == 0x0f {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = ((lsb & 0x03) * 2 + 1) as usize;
+            2usize.saturating_pow(exponent).saturating_mul(multiplier)
         }
+        else {
+            (((msb as usize) << 8) | lsb as usize) * unit
+        }
+    }
+
+    // NES 2.0 RAM sizes are shift counts, 64 << n bytes, 0 means none
+    fn ram_size(shift: u8) -> usize {
+        if shift == 0 { 0 } else { 64 << shift }
     }
 }
 
 impl Cartridge {
-    pub fn new(filename: &str) -> Self {
-        // This is all for type 1 files
-        let file = Self::read_file(filename);
-        let header = Header::new(&file);
-        println!("{:?}", header);
-        let prg_size = header.prg_rom_chunks as usize * 0x4000;
-        let chr_size = header.chr_rom_chunks as usize * 0x2000;
-        
-        let mut offset = 16; // Header + padding (training)
-        let mut prg_mem = file[offset..min(offset+prg_size, file.len())].to_vec();
-        prg_mem.resize(prg_size, 0);
-        //println!("{:?}", prg_mem);
-        offset += prg_size;
-        let mut chr_mem = file[offset..min(offset+chr_size, file.len())].to_vec();
-        chr_mem.resize(chr_size, 0);
- 
-        let me = Self {
-            mapper: Self::get_mapper(&header),
-            header: header,
-            prg_mem: prg_mem,
-            chr_mem: chr_mem,
+    pub fn new(filename: &str) -> Result<Self, CartridgeError> {
+        let mut cartridge = Self::from_reader(File::open(filename)?)?;
+        cartridge.save_path = Some(Path::new(filename).with_extension("sav"));
+        Ok(cartridge)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, CartridgeError> {
+        let mut buffer = vec![];
+        reader.read_to_end(&mut buffer)?;
+        Self::from_bytes(&buffer)
+    }
+
+    pub fn from_bytes(file: &[u8]) -> Result<Self, CartridgeError> {
+        let header = Header::new(file)?;
+        let prg_size = header.prg_rom_size;
+        let chr_size = header.chr_rom_size;
+
+        // Everything is checked against the file before allocating, as
+        // NES 2.0 exponent sizes can claim far more ROM than is there
+        let mut offset = 16; // Header
+
+        // A 512 byte trainer sits between the header and PRG, and is loaded to $7000-$71FF
+        let trainer = if header.trainer {
+            let trainer = Self::slice(file, offset, 0x200)
+                .ok_or(CartridgeError::TruncatedTrainer { expected: 0x200, found: file.len() - offset })?;
+            offset += 0x200;
+            Some(trainer)
+        }
+        else {
+            None
         };
 
-        me
+        let prg_rom = Self::slice(file, offset, prg_size)
+            .ok_or(CartridgeError::TruncatedPrg { expected: prg_size, found: file.len() - offset })?;
+        offset += prg_size;
+        let chr_rom = Self::slice(file, offset, chr_size)
+            .ok_or(CartridgeError::TruncatedChr { expected: chr_size, found: file.len() - offset })?;
+
+        let mut prg_ram = vec![0; header.prg_ram_size + header.prg_nvram_size];
+        if let Some(trainer) = trainer {
+            if prg_ram.len() < 0x2000 {
+                prg_ram.resize(0x2000, 0);
+            }
+            prg_ram[0x1000..0x1200].copy_from_slice(trainer);
+        }
+
+        let prg_mem = prg_rom.to_vec();
+        let mut chr_mem = chr_rom.to_vec();
+
+        let chr_ram = chr_size == 0;
+        if chr_ram {
+            let size = header.chr_ram_size + header.chr_nvram_size;
+            chr_mem = vec![0; if size == 0 { 0x2000 } else { size }];
+        }
+
+        Ok(Self {
+            mapper: Self::get_mapper(&header)?,
+            header,
+            prg_mem,
+            chr_mem,
+            chr_ram,
+            prg_ram,
+            save_path: None,
+        })
     }
 
-    fn read_file(filename: &str) -> Vec<u8> {
-        let mut f = File::open(&filename).expect("no file found");
-        let metadata = std::fs::metadata(&filename).expect("unable to read metadata");
-        let mut buffer = vec![0; metadata.len() as usize];
-        f.read(&mut buffer).expect("buffer overflow");
-        return buffer;
+    // Mapper offsets wrap around memory smaller than the banks they select
+    fn wrapped(mem: &[u8], offset: usize) -> Option<&u8> {
+        if mem.is_empty() {
+            None
+        }
+        else {
+            mem.get(offset % mem.len())
+        }
+    }
+
+    fn slice(file: &[u8], offset: usize, size: usize) -> Option<&[u8]> {
+        file.get(offset..offset.checked_add(size)?)
+    }
+
+    // Set where the save file lives, for cartridges not loaded from a path
+    pub fn set_save_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.save_path = Some(path.as_ref().to_path_buf());
+    }
+
+    fn has_save(&self) -> bool {
+        self.header.battery && !(self.prg_ram.is_empty() && self.mapper.battery_ram().is_empty())
+    }
+
+    // Restore battery backed PRG-RAM and mapper RAM, a missing save file is not an error
+    pub fn load_save(&mut self) -> std::io::Result<()> {
+        if !self.has_save() {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.save_path {
+            match std::fs::read(path) {
+                Ok(data) => {
+                    let len = data.len().min(self.prg_ram.len());
+                    self.prg_ram[..len].copy_from_slice(&data[..len]);
+
+                    let data = &data[len..];
+                    let battery_ram = self.mapper.battery_ram_mut();
+                    let len = data.len().min(battery_ram.len());
+                    battery_ram[..len].copy_from_slice(&data[..len]);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
     }
 
-    fn get_mapper(header: &Header) -> Box<dyn Mapper> {
-        println!("Using Mapper: {}", header.mapper_id);
+    pub fn write_save(&self) -> std::io::Result<()> {
+        if !self.has_save() {
+            return Ok(());
+        }
 
+        match &self.save_path {
+            Some(path) => std::fs::write(path, [&self.prg_ram[..], self.mapper.battery_ram()].concat()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn cpu_clock(&mut self) {
+        self.mapper.cpu_clock();
+    }
+
+    pub fn ppu_address(&mut self, addr: u16) {
+        self.mapper.ppu_address(addr);
+    }
+
+    pub fn audio(&self) -> f32 {
+        self.mapper.audio()
+    }
+
+    fn get_mapper(header: &Header) -> Result<Box<dyn Mapper>, CartridgeError> {
         match header.mapper_id {
-            0 => Box::new(Mapper0::new(header.prg_rom_chunks)),
-            _ => panic!("Unknown mapper id: {}", header.mapper_id)
+            0 => Ok(Box::new(Mapper0::new(header))),
+            1 => Ok(Box::new(Mapper1::new(header))),
+            2 => Ok(Box::new(Mapper2::new(header))),
+            3 => Ok(Box::new(Mapper3::new(header))),
+            4 => Ok(Box::new(Mapper4::new(header))),
+            5 => Ok(Box::new(Mapper5::new(header))),
+            7 => Ok(Box::new(Mapper7::new(header))),
+            9 => Ok(Box::new(Mapper9::new(header))),
+            10 => Ok(Box::new(Mapper10::new(header))),
+            19 => Ok(Box::new(Mapper19::new(header))),
+            21 | 22 | 23 | 25 => Ok(Box::new(Mapper21::new(header))),
+            24 | 26 => Ok(Box::new(Mapper24::new(header))),
+            69 => Ok(Box::new(Mapper69::new(header))),
+            85 => Ok(Box::new(Mapper85::new(header))),
+            _ => Err(CartridgeError::UnsupportedMapper(header.mapper_id))
         }
     }
     
-    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
-        if let Some(a) = self.mapper.read(addr) {
-            //println!("Reading: {:#04x}, {}", a, self.prg_mem[a as usize]);
-            return Some(self.chr_mem[a as usize]);
+    // Save state of the board: mapper registers and any RAM it carries
+    pub fn save_state(&self) -> Vec<u8> {
+        let mapper = self.mapper.save_state();
+        let mut state = StateWriter::new()
+            .u64(mapper.len() as u64)
+            .bytes(&mapper)
+            .bytes(&self.prg_ram);
+        if self.chr_ram {
+            state = state.bytes(&self.chr_mem);
+        }
+        state.finish()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        let len = r.u64()? as usize;
+        let mapper = r.bytes(len)?;
+        let prg_ram = r.bytes(self.prg_ram.len())?;
+        let chr_mem = if self.chr_ram { Some(r.bytes(self.chr_mem.len())?) } else { None };
+        r.finish()?;
+
+        self.mapper.load_state(mapper)?;
+        self.prg_ram.copy_from_slice(prg_ram);
+        if let Some(chr_mem) = chr_mem {
+            self.chr_mem.copy_from_slice(chr_mem);
+        }
+        Ok(())
+    }
+
+    // PPU $0000-$3EFF. CHR is resolved here, CIRAM lives in the console
+    // so those offsets are handed back to the PPU bus
+    pub fn ppu_read(&mut self, addr: u16) -> Mapped {
+        let mapped = if addr < 0x2000 {
+            self.mapper.ppu_read(addr)
+        }
+        else {
+            self.mapper.nametable_read(addr)
+        };
+
+        match mapped {
+            Mapped::Chr(a) => Self::wrapped(&self.chr_mem, a).map_or(Mapped::None, |d| Mapped::Data(*d)),
+            Mapped::PrgRom(a) => Self::wrapped(&self.prg_mem, a).map_or(Mapped::None, |d| Mapped::Data(*d)),
+            Mapped::PrgRam(a) => Self::wrapped(&self.prg_ram, a).map_or(Mapped::None, |d| Mapped::Data(*d)),
+            other => other,
+        }
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        let mapped = if addr < 0x2000 {
+            self.mapper.ppu_write(addr, data)
+        }
+        else {
+            self.mapper.nametable_write(addr, data)
+        };
+
+        match mapped {
+            Mapped::Chr(a) => {
+                if self.chr_ram {
+                    Self::write_wrapped(&mut self.chr_mem, a, data);
+                }
+                Mapped::None
+            }
+            Mapped::PrgRam(a) => {
+                Self::write_wrapped(&mut self.prg_ram, a, data);
+                Mapped::None
+            }
+            other => other,
         }
-        None
     }
 
-    fn ppu_write(&mut self, addr: u16, data: u8) {
-        if let Some(a) = self.mapper.write(addr, data) {
-            //println!("Reading: {:#04x}, {}", a, self.prg_mem[a as usize]);
-            //self.chr_mem[a as usize]);
+    fn write_wrapped(mem: &mut [u8], offset: usize, data: u8) {
+        if !mem.is_empty() {
+            let len = mem.len();
+            mem[offset % len] = data;
         }
     }
 
+    fn cpu_mapped_read(&self, mapped: Mapped) -> Option<u8> {
+        match mapped {
+            Mapped::PrgRom(a) => Self::wrapped(&self.prg_mem, a).copied(),
+            Mapped::PrgRam(a) => Self::wrapped(&self.prg_ram, a).copied(),
+            Mapped::Chr(a) => Self::wrapped(&self.chr_mem, a).copied(),
+            Mapped::Data(d) => Some(d),
+            _ => None,
+        }
+    }
 }
 
 impl BusDevice for Cartridge {
     fn read(&mut self, addr: u16) -> Option<u8> {
+        if addr < 0x4020 {
+            return None;
+        }
 
-        if (0x8000..=0xFFFF).contains(&addr) {
-            if let Some(a) = self.mapper.read(addr) {
-                //println!("Reading: {:#04x}, {}", a, self.prg_mem[a as usize]);
-                return Some(self.prg_mem[a as usize]);
-            }
-            None
+        let mapped = self.mapper.cpu_read(addr);
+        self.cpu_mapped_read(mapped)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        let mut data = data;
+        if addr >= 0x8000 && self.mapper.bus_conflicts() {
+            let mapped = self.mapper.cpu_read(addr);
+            data &= self.cpu_mapped_read(mapped).unwrap_or(0xff);
         }
-        else {
-            None
+
+        // Mapper registers are written here, the ROM itself can't be
+        match self.mapper.cpu_write(addr, data) {
+            Mapped::PrgRam(a) => Self::write_wrapped(&mut self.prg_ram, a, data),
+            Mapped::Chr(a) if self.chr_ram => Self::write_wrapped(&mut self.chr_mem, a, data),
+            _ => {}
         }
     }
 
-    fn write(&mut self, addr: u16, data: u8) {
-        if (0x8000..=0xFFFF).contains(&addr) {
-            //self.memory[(addr as usize) & 0x7ff] = data;
+    fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bytes: &[u8; 16]) -> Header {
+        Header::new(bytes).unwrap()
+    }
+
+    #[test]
+    fn ines_header() {
+        let h = header(b"NES\x1a\x02\x01\x13\x40\x00\x01\x00\x00\x00\x00\x00\x00");
+        assert_eq!(h.format, HeaderFormat::INes);
+        assert_eq!(h.mapper_id, 0x41);
+        assert_eq!(h.submapper, 0);
+        assert_eq!(h.prg_rom_size, 0x8000);
+        assert_eq!(h.chr_rom_size, 0x2000);
+        // 0 means 8KB
+        assert_eq!(h.prg_ram_size, 0x2000);
+        assert_eq!(h.mirroring, Mirroring::Vertical);
+        assert!(h.battery);
+        assert!(!h.trainer);
+        assert_eq!(h.timing, Timing::Pal);
+        assert_eq!(h.console, ConsoleType::Nes);
+    }
+
+    #[test]
+    fn ines_header_ignores_byte_7_with_junk_after_it() {
+        let h = header(b"NES\x1a\x01\x01\x18DiskDude!");
+        assert_eq!(h.mapper_id, 0x01);
+        assert_eq!(h.mirroring, Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn nes20_header() {
+        let h = header(b"NES\x1a\x02\x04\x04\x1b\x31\x21\x70\x07\x03\x05\x00\x2a");
+        assert_eq!(h.format, HeaderFormat::Nes20);
+        assert_eq!(h.mapper_id, 0x110);
+        assert_eq!(h.submapper, 3);
+        assert_eq!(h.prg_rom_size, 0x102 * 0x4000);
+        assert_eq!(h.chr_rom_size, 0x204 * 0x2000);
+        assert_eq!(h.prg_ram_size, 0);
+        assert_eq!(h.prg_nvram_size, 0x2000);
+        assert_eq!(h.chr_ram_size, 0x2000);
+        assert_eq!(h.chr_nvram_size, 0);
+        assert!(h.trainer);
+        assert_eq!(h.timing, Timing::Dendy);
+        assert_eq!(h.console, ConsoleType::Extended(5));
+        assert_eq!(h.expansion_device, 0x2a);
+    }
+
+    #[test]
+    fn nes20_exponent_sizes() {
+        // 2^10 * 3 PRG, 2^4 * 1 CHR
+        let h = header(b"NES\x1a\x29\x10\x00\x08\x00\xff\x00\x00\x00\x00\x00\x00");
+        assert_eq!(h.prg_rom_size, 3072);
+        assert_eq!(h.chr_rom_size, 16);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(Header::new(b"NES"), Err(CartridgeError::BadMagic)));
+        assert!(matches!(Header::new(b"UNIF\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"), Err(CartridgeError::BadMagic)));
+        assert!(matches!(Header::new(b"NES\x1a\x01\x01"), Err(CartridgeError::TruncatedHeader)));
+    }
+
+    #[test]
+    fn oversized_exponent_roms_are_truncated_not_a_panic() {
+        let prg = Cartridge::from_bytes(b"NES\x1a\xff\x00\x00\x08\x00\x0f\x00\x00\x00\x00\x00\x00");
+        assert!(matches!(prg, Err(CartridgeError::TruncatedPrg { found: 0, .. })));
+
+        let chr = Cartridge::from_bytes(b"NES\x1a\x00\xff\x00\x08\x00\xf0\x00\x00\x00\x00\x00\x00");
+        assert!(matches!(chr, Err(CartridgeError::TruncatedChr { found: 0, .. })));
+    }
+
+    #[test]
+    fn truncated_roms() {
+        let mut file = b"NES\x1a\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        file.resize(16 + 0x4000 + 0x1000, 0);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(CartridgeError::TruncatedChr { expected: 0x2000, found: 0x1000 })
+        ));
+
+        file.truncate(16 + 0x100);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(CartridgeError::TruncatedPrg { expected: 0x4000, found: 0x100 })
+        ));
+    }
+
+    // Header, then trainer if flagged, one bank of PRG starting $AA and one of CHR
+    fn rom_with_trainer(header: &[u8; 16]) -> Vec<u8> {
+        let mut file = header.to_vec();
+        if header[6] & 0x04 != 0 {
+            file.extend((0..0x200).map(|n| n as u8 ^ 0x5a));
         }
+        file.push(0xaa);
+        file.resize(file.len() + 0x3fff + 0x2000, 0);
+        file
+    }
+
+    #[test]
+    fn trainer_loads_at_7000() {
+        let file = rom_with_trainer(b"NES\x1a\x01\x01\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        let mut cartridge = Cartridge::from_bytes(&file).unwrap();
+        for n in 0..0x200u16 {
+            assert_eq!(cartridge.read(0x7000 + n), Some(n as u8 ^ 0x5a));
+        }
+        assert_eq!(cartridge.read(0x6fff), Some(0));
+        assert_eq!(cartridge.read(0x7200), Some(0));
+        // PRG starts after the trainer
+        assert_eq!(cartridge.read(0x8000), Some(0xaa));
+    }
+
+    #[test]
+    fn trainer_gets_prg_ram_when_header_has_none() {
+        // NES 2.0, no PRG-RAM
+        let file = rom_with_trainer(b"NES\x1a\x01\x01\x04\x08\x00\x00\x00\x00\x00\x00\x00\x00");
+        let mut cartridge = Cartridge::from_bytes(&file).unwrap();
+        assert_eq!(cartridge.prg_ram.len(), 0x2000);
+        assert_eq!(cartridge.read(0x7001), Some(0x5b));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn truncated_trainer() {
+        let file = b"NES\x1a\x01\x01\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert!(matches!(
+            Cartridge::from_bytes(file),
+            Err(CartridgeError::TruncatedTrainer { expected: 0x200, found: 2 })
+        ));
+
+        // A whole trainer, then nothing
+        let mut file = file.to_vec();
+        file.resize(16 + 0x200, 0);
+        assert!(matches!(
+            Cartridge::from_bytes(&file),
+            Err(CartridgeError::TruncatedPrg { expected: 0x4000, found: 0 })
+        ));
+    }
+
+    // UxROM on a NES 2.0 `submapper`, the ROM at $8000-$BFFF reads $05 in bank 0
+    // and the bank number in banks 1-3
+    fn uxrom(submapper: u8) -> Cartridge {
+        let mut file = b"NES\x1a\x04\x01\x20\x08\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        file[8] = submapper << 4;
+        file.extend(vec![0x05; 0x4000]);
+        for bank in 1..4 {
+            file.extend(vec![bank; 0x4000]);
+        }
+        file.resize(file.len() + 0x2000, 0);
+        Cartridge::from_bytes(&file).unwrap()
+    }
+
+    #[test]
+    fn bus_conflicts_and_the_written_value_with_rom() {
+        let mut cartridge = uxrom(2);
+        cartridge.write(0x8000, 0x03);
+        assert_eq!(cartridge.read(0x8000), Some(0x01));
+    }
+
+    #[test]
+    fn no_bus_conflicts_without_submapper_2() {
+        let mut cartridge = uxrom(1);
+        cartridge.write(0x8000, 0x03);
+        assert_eq!(cartridge.read(0x8000), Some(0x03));
+    }
+
+    // MMC1 with 8KB of PRG-RAM, the first byte of each 16KB PRG bank is its number
+    fn mmc1() -> Cartridge {
+        let mut file = b"NES\x1a\x08\x01\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        for bank in 0..8 {
+            file.push(bank);
+            file.resize(file.len() + 0x3fff, 0);
+        }
+        file.resize(file.len() + 0x2000, 0);
+        Cartridge::from_bytes(&file).unwrap()
+    }
+
+    #[test]
+    fn save_state_restores_mapper_registers_and_prg_ram() {
+        let mut cartridge = mmc1();
+        for bit in 0..5 {
+            cartridge.write(0xe000, (0x05 >> bit) & 0x01);
+            cartridge.cpu_clock();
+            cartridge.cpu_clock();
+        }
+        cartridge.write(0x6000, 0x77);
+        let state = cartridge.save_state();
+
+        let mut restored = mmc1();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.read(0x8000), Some(5));
+        assert_eq!(restored.read(0x6000), Some(0x77));
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn bad_save_states_are_rejected_untouched() {
+        let mut cartridge = mmc1();
+        cartridge.write(0x6000, 0x77);
+        let mut state = cartridge.save_state();
+
+        let mut other = mmc1();
+        assert!(other.load_state(&state[..state.len() - 1]).is_err());
+        state.push(0);
+        assert!(other.load_state(&state).is_err());
+        assert_eq!(other.read(0x6000), Some(0x00));
+    }
+}
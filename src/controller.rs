@@ -0,0 +1,62 @@
+use crate::nes::BusDevice;
+
+// Standard controller on $4016 (port 0) or $4017 (port 1)
+#[derive(Debug)]
+pub struct Controller {
+    addr: u16,
+    buttons: u8,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new(port: usize) -> Self {
+        Self {
+            addr: 0x4016 + port as u16,
+            buttons: 0,
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    // One bit per button in the order they are shifted out:
+    // A, B, Select, Start, Up, Down, Left, Right from bit 0 up
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+}
+
+impl BusDevice for Controller {
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        if addr != self.addr {
+            return None;
+        }
+
+        let bit = if self.strobe {
+            // While strobe is high the shift register keeps reloading, so A is returned
+            self.buttons & 0x01
+        }
+        else {
+            let bit = self.shift & 0x01;
+            // Official controllers return 1 once all eight buttons are read
+            self.shift = (self.shift >> 1) | 0x80;
+            bit
+        };
+
+        // Only D0 is driven, the upper bits are left from the $40 address byte
+        Some(0x40 | bit)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        // Both ports share the strobe line on $4016
+        if addr == 0x4016 {
+            self.strobe = data & 0x01 != 0;
+            if self.strobe {
+                self.shift = self.buttons;
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::nes::BusDevice;
+use crate::nes::Bus;
+
+// Latches writes to $4014, the transfer itself is run by Nes::clock
+#[derive(Debug)]
+pub struct OamDma {
+    request: Option<u8>,
+}
+
+impl OamDma {
+    pub fn new() -> Self {
+        Self {
+            request: None,
+        }
+    }
+
+    pub fn take_request(&mut self) -> Option<u8> {
+        self.request.take()
+    }
+}
+
+impl BusDevice for OamDma {
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr == 0x4014 {
+            self.request = Some(data);
+        }
+    }
+}
+
+// Copies page $XX00-$XXFF to $2004, one CPU cycle per clock.
+// One halt cycle, plus one more to align when started on an odd cycle,
+// then 256 read/write pairs: 513 or 514 cycles in total.
+#[derive(Debug)]
+pub struct OamDmaTransfer {
+    page: u8,
+    wait: u8,
+    step: u16,
+    data: u8,
+}
+
+impl OamDmaTransfer {
+    pub fn new(page: u8, odd_cycle: bool) -> Self {
+        Self {
+            page,
+            wait: if odd_cycle { 2 } else { 1 },
+            step: 0,
+            data: 0,
+        }
+    }
+
+    // Returns true once the last byte has been written
+    pub fn clock(&mut self, bus: &mut Bus) -> bool {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return false;
+        }
+
+        let index = self.step >> 1;
+        if self.step & 0x01 == 0 {
+            self.data = bus.read(((self.page as u16) << 8) | index);
+        }
+        else {
+            bus.write(0x2004, self.data);
+        }
+
+        self.step += 1;
+        self.step == 512
+    }
+}
@@ -3,26 +3,143 @@ mod nes;
 mod ram;
 mod cartridge;
 mod ppu;
+mod apu;
+mod audio;
+mod controller;
+mod dma;
 mod mappers;
+mod state;
 
 use std::{thread, time};
 use std::cell::RefCell;
 use std::rc::Rc;
 
+// Frames between writes of the save file while running freely, about a second
+const SAVE_INTERVAL: usize = 60;
+
+fn usage() -> ! {
+    eprintln!("Usage: nes [rom] [--info] [--trace] [--input <file>] [--wav <file>] [--screenshot <file>] [--frames <n>] [--sample-rate <hz>]");
+    std::process::exit(1);
+}
+
+fn print_info(header: &cartridge::Header) {
+    println!("Format: {:?}", header.format);
+    println!("Mapper: {}, submapper {}", header.mapper_id, header.submapper);
+    println!("PRG-ROM: {} bytes, CHR-ROM: {} bytes", header.prg_rom_size, header.chr_rom_size);
+    println!("PRG-RAM: {} bytes, {} battery backed", header.prg_ram_size, header.prg_nvram_size);
+    println!("CHR-RAM: {} bytes, {} battery backed", header.chr_ram_size, header.chr_nvram_size);
+    println!("Mirroring: {:?}, battery: {}, trainer: {}", header.mirroring, header.battery, header.trainer);
+    println!("Timing: {:?}, console: {:?}, expansion device: {}", header.timing, header.console, header.expansion_device);
+}
+
+// Recorded input is two bytes a frame, the buttons held on ports 0 and 1.
+// Everything is released once the recording runs out
+fn set_input(nes: &mut nes::Nes, input: &[u8], frame: usize) {
+    let buttons = input.get(frame * 2..frame * 2 + 2).unwrap_or(&[0, 0]);
+    nes.set_buttons(0, buttons[0]);
+    nes.set_buttons(1, buttons[1]);
+}
 
 fn main() {
+    let mut rom = String::from("Super_mario_brothers.nes");
+    let mut input: Option<String> = None;
+    let mut wav: Option<String> = None;
+    let mut screenshot: Option<String> = None;
+    let mut frames: u32 = 600;
+    let mut sample_rate: u32 = 44100;
+    let mut info = false;
+    let mut trace = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--info" => info = true,
+            "--trace" => trace = true,
+            "--input" => input = Some(args.next().unwrap_or_else(|| usage())),
+            "--wav" => wav = Some(args.next().unwrap_or_else(|| usage())),
+            "--screenshot" => screenshot = Some(args.next().unwrap_or_else(|| usage())),
+            "--frames" => frames = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            "--sample-rate" => sample_rate = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            _ if arg.starts_with("--") => usage(),
+            _ => rom = arg,
+        }
+    }
+
     let mut nes = nes::Nes::new();
-    let cartridge = cartridge::Cartridge::new("Super_mario_brothers.nes");
+    let cartridge = match cartridge::Cartridge::new(&rom) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("Unable to load {}: {}", rom, e);
+            std::process::exit(1);
+        }
+    };
 
-    nes.insert(cartridge);
+    if info {
+        print_info(cartridge.header());
+        return;
+    }
+
+    let input = match input {
+        Some(path) => std::fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("Unable to read {}: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => vec![],
+    };
+
+    if let Err(e) = nes.insert(cartridge) {
+        eprintln!("Unable to load save for {}: {}", rom, e);
+        std::process::exit(1);
+    }
     nes.reset();
+    nes.set_trace(trace);
 
+    // Headless: run a fixed number of frames, then dump the audio and the picture
+    if wav.is_some() || screenshot.is_some() {
+        if wav.is_some() {
+            nes.enable_audio(sample_rate);
+        }
+        let mut samples = vec![];
+        for frame in 0..frames as usize {
+            set_input(&mut nes, &input, frame);
+            nes.run_frame();
+            samples.append(&mut nes.drain_audio());
+        }
+
+        if let Some(wav) = wav {
+            if let Err(e) = audio::write_wav(&wav, sample_rate, &samples) {
+                eprintln!("Unable to write {}: {}", wav, e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(path) = screenshot {
+            if let Err(e) = nes.screenshot(&path) {
+                eprintln!("Unable to write {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        if let Err(e) = nes.eject() {
+            eprintln!("Unable to write save for {}: {}", rom, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // This only stops when the process is killed, so the save file is kept current as it goes
     //println!("{:?}", cpu);
-    loop {
+    for frame in 0.. {
         //thread::sleep(time::Duration::from_millis(10));
         //cpu.next_inst();
         //println!("{:?}", cpu);
-        nes.clock();
+        set_input(&mut nes, &input, frame);
+        nes.run_frame();
+
+        if frame % SAVE_INTERVAL == SAVE_INTERVAL - 1 {
+            if let Err(e) = nes.flush_save() {
+                eprintln!("Unable to write save for {}: {}", rom, e);
+                std::process::exit(1);
+            }
+        }
     }
 
    
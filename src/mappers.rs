@@ -1,10 +1,111 @@
 pub mod mapper0;
+pub mod mapper1;
+pub mod mapper2;
+pub mod mapper3;
+pub mod mapper4;
+pub mod mapper5;
+pub mod mapper7;
+pub mod mapper9;
+pub mod mapper10;
+pub mod mapper19;
+pub mod mapper21;
+pub mod mapper24;
+pub mod mapper69;
+pub mod mapper85;
+pub mod vrc;
 
+#[cfg(test)]
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+use crate::state::InvalidState;
+
+// Where a CPU or PPU access through the cartridge lands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mapped {
+    // Offset into PRG ROM
+    PrgRom(usize),
+    // Offset into PRG RAM
+    PrgRam(usize),
+    // Offset into CHR ROM or RAM
+    Chr(usize),
+    // Offset into the nametable RAM inside the console (CIRAM)
+    Ciram(usize),
+    // The mapper supplies the data itself (registers, internal RAM)
+    Data(u8),
+    // Nothing drives the bus, or a write was taken by the mapper
+    None,
+}
 
 pub trait Mapper: std::fmt::Debug {
-    fn read(&mut self, addr: u16) -> Option<u16>;
-    fn write(&mut self, addr: u16, data: u8) -> Option<u16>;
+    // CPU $4020-$FFFF. Writes see every CPU address so boards can snoop registers
+    fn cpu_read(&mut self, addr: u16) -> Mapped;
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped;
+
+    // PPU $0000-$1FFF
+    fn ppu_read(&mut self, addr: u16) -> Mapped;
+    fn ppu_write(&mut self, addr: u16, data: u8) -> Mapped;
+
+    // PPU $2000-$3EFF, mapped onto CIRAM by the current mirroring unless overridden
+    fn nametable_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Ciram(self.mirroring().ciram_offset(addr))
+    }
+
+    fn nametable_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Ciram(self.mirroring().ciram_offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Boards where the ROM drives the bus during register writes
+    // see the written value ANDed with the ROM byte at that address.
+    // For the discrete boards the NES 2.0 submapper says which it is:
+    // 2 has AND-type bus conflicts, 1 has none
+    fn bus_conflicts(&self) -> bool {
+        false
+    }
+
+    // Called once per CPU cycle
+    fn cpu_clock(&mut self) {}
+
+    // Sees every address the PPU puts on its bus (pattern and nametable fetches)
+    fn ppu_address(&mut self, _addr: u16) {}
+
+    // Asserts the CPU IRQ line while true
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Expansion audio, mixed in with the APU output
+    fn audio(&self) -> f32 {
+        0.0
+    }
+
+    // RAM inside the mapper chip kept alive by the battery, saved after PRG-RAM
+    fn battery_ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn battery_ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    // Internal registers, for save states
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), InvalidState> {
+        Ok(())
+    }
+}
 
-    fn ppu_read(&mut self, addr: u16) -> Option<u16>;
-    fn ppu_write(&mut self, addr: u16, data: u8) -> Option<u16>;
-}
\ No newline at end of file
+// iNES header for mapper tests, sized in 16KB PRG-ROM and 8KB CHR-ROM banks
+#[cfg(test)]
+pub fn test_header(prg_16k: u8, chr_8k: u8, mapper_id: u8) -> Header {
+    let mut file = *b"NES\x1a\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+    file[4] = prg_16k;
+    file[5] = chr_8k;
+    file[6] = mapper_id << 4;
+    file[7] = mapper_id & 0xf0;
+    Header::new(&file).unwrap()
+}
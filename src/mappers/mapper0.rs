@@ -1,44 +1,57 @@
 use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
 
 #[derive(Debug)]
 pub struct Mapper0 {
     prg_rom_banks: u8,
     mask:u16,
+    mirroring: Mirroring,
 }
 
 
 impl Mapper0 {
-    pub fn new(prg_rom_banks: u8) -> Self {
+    pub fn new(header: &Header) -> Self {
+        let prg_rom_banks = (header.prg_rom_size / 0x4000) as u8;
         Self {
-            prg_rom_banks: prg_rom_banks,
+            prg_rom_banks,
             mask: if prg_rom_banks > 1 {
                 0x7fff
             }
             else {
                 0x3fff
-            }
+            },
+            mirroring: header.mirroring,
         }
     }
 }
 
 impl Mapper for Mapper0 {
-    fn read(&mut self, addr: u16) -> Option<u16> {
-        if addr >= 0x8000 && addr <= 0xFFFF {
-            return Some(addr & self.mask)
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        if (0x6000..0x8000).contains(&addr) {
+            return Mapped::PrgRam((addr & 0x1fff) as usize)
+        }
+        if addr >= 0x8000 {
+            return Mapped::PrgRom((addr & self.mask) as usize)
         }
-        None
+        Mapped::None
     }
-    fn write(&mut self, addr: u16, data: u8) -> Option<u16> {
-        if addr >= 0x8000 && addr <= 0xFFFF {
-            return Some(addr & self.mask)
+    fn cpu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        if (0x6000..0x8000).contains(&addr) {
+            return Mapped::PrgRam((addr & 0x1fff) as usize)
         }
-        None
+        Mapped::None
     }
 
-    fn ppu_read(&mut self, addr: u16) -> Option<u16> {
-        Some(addr)
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(addr as usize)
     }
-    fn ppu_write(&mut self, addr: u16, data: u8) -> Option<u16> {
-        Some(addr)
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(addr as usize)
     }
-}
\ No newline at end of file
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
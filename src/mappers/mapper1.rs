@@ -0,0 +1,271 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// MMC1 (SxROM)
+// Registers are loaded serially through a 5 bit shift register:
+// $8000 control, $A000 CHR bank 0, $C000 CHR bank 1, $E000 PRG bank
+#[derive(Debug)]
+pub struct Mapper1 {
+    prg_rom_banks: usize,
+    prg_ram_size: usize,
+    shift: u8,
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8,
+    cycle: u64,
+    last_write: Option<u64>,
+}
+
+impl Mapper1 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: header.prg_rom_size / 0x4000,
+            prg_ram_size: header.prg_ram_size + header.prg_nvram_size,
+            shift: 0x10,
+            // Power on with the last bank fixed at $C000
+            control: 0x0c,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_write: None,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        // Writes on back to back cycles (the dummy write of a RMW instruction) are ignored
+        let consecutive = matches!(self.last_write, Some(cycle) if self.cycle <= cycle + 1);
+        self.last_write = Some(self.cycle);
+        if consecutive {
+            return;
+        }
+
+        if data & 0x80 != 0 {
+            self.shift = 0x10;
+            self.control |= 0x0c;
+            return;
+        }
+
+        let complete = self.shift & 0x01 != 0;
+        self.shift = (self.shift >> 1) | ((data & 0x01) << 4);
+        if complete {
+            match (addr >> 13) & 0x03 {
+                0 => self.control = self.shift,
+                1 => self.chr_bank0 = self.shift,
+                2 => self.chr_bank1 = self.shift,
+                _ => self.prg_bank = self.shift,
+            }
+            self.shift = 0x10;
+        }
+    }
+
+    // SUROM/SXROM use CHR bank 0 bit 4 to pick the 256KB half of a 512KB PRG ROM
+    fn prg_outer_bank(&self) -> usize {
+        if self.prg_rom_banks > 16 {
+            (self.chr_bank0 & 0x10) as usize
+        }
+        else {
+            0
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let outer = self.prg_outer_bank();
+        let bank = (self.prg_bank & 0x0f) as usize;
+        let last = (self.prg_rom_banks.saturating_sub(1)) & 0x0f;
+
+        let bank = match (self.control >> 2) & 0x03 {
+            // 32KB, ignoring the low bit of the bank number
+            0 | 1 => (bank & 0x0e) | ((addr as usize >> 14) & 0x01),
+            // First bank fixed at $8000
+            2 => if addr < 0xc000 { 0 } else { bank },
+            // Last bank fixed at $C000
+            _ => if addr < 0xc000 { bank } else { last },
+        };
+
+        ((outer | bank) * 0x4000) | (addr as usize & 0x3fff)
+    }
+
+    // SOROM (16KB) and SXROM (32KB) select 8KB PRG-RAM banks with CHR bank 0 bits 2-3
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        if self.prg_bank & 0x10 != 0 {
+            return None;
+        }
+
+        let bank = match self.prg_ram_size {
+            0x8000 => ((self.chr_bank0 >> 2) & 0x03) as usize,
+            0x4000 => ((self.chr_bank0 >> 3) & 0x01) as usize,
+            _ => 0,
+        };
+        Some((bank * 0x2000) | (addr as usize & 0x1fff))
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        if self.control & 0x10 == 0 {
+            // 8KB mode ignores the low bit
+            ((self.chr_bank0 & 0x1e) as usize * 0x1000) | (addr as usize & 0x1fff)
+        }
+        else if addr < 0x1000 {
+            (self.chr_bank0 as usize * 0x1000) | (addr as usize & 0x0fff)
+        }
+        else {
+            (self.chr_bank1 as usize * 0x1000) | (addr as usize & 0x0fff)
+        }
+    }
+}
+
+impl Mapper for Mapper1 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram_offset(addr).map_or(Mapped::None, Mapped::PrgRam),
+            0x8000..=0xffff => Mapped::PrgRom(self.prg_offset(addr)),
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram_offset(addr).map_or(Mapped::None, Mapped::PrgRam),
+            0x8000..=0xffff => {
+                self.write_register(addr, data);
+                Mapped::None
+            }
+            _ => Mapped::None,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .u8(self.shift)
+            .u8(self.control)
+            .u8(self.chr_bank0)
+            .u8(self.chr_bank1)
+            .u8(self.prg_bank)
+            .u64(self.cycle)
+            .bool(self.last_write.is_some())
+            .u64(self.last_write.unwrap_or(0))
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.shift = r.u8()?;
+        self.control = r.u8()?;
+        self.chr_bank0 = r.u8()?;
+        self.chr_bank1 = r.u8()?;
+        self.prg_bank = r.u8()?;
+        self.cycle = r.u64()?;
+        let written = r.bool()?;
+        let last_write = r.u64()?;
+        self.last_write = if written { Some(last_write) } else { None };
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_header;
+
+    // 128KB PRG-ROM, 8KB CHR-ROM
+    fn mapper() -> Mapper1 {
+        Mapper1::new(&test_header(8, 1, 1))
+    }
+
+    // Load a register one bit at a time, the way games do, with a gap between writes
+    fn write_serial(mapper: &mut Mapper1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(addr, (value >> bit) & 0x01);
+            mapper.cpu_clock();
+            mapper.cpu_clock();
+        }
+    }
+
+    #[test]
+    fn five_writes_load_a_register() {
+        let mut mapper = mapper();
+        write_serial(&mut mapper, 0xe000, 0x05);
+        assert_eq!(mapper.cpu_read(0x8000), Mapped::PrgRom(5 * 0x4000));
+        // Last bank stays fixed at $C000
+        assert_eq!(mapper.cpu_read(0xc000), Mapped::PrgRom(7 * 0x4000));
+    }
+
+    #[test]
+    fn register_is_picked_by_the_fifth_write() {
+        let mut mapper = mapper();
+        for (n, addr) in [0xe000, 0xe000, 0xe000, 0xe000, 0x8000].iter().enumerate() {
+            mapper.cpu_write(*addr, (0x1b >> n) & 0x01);
+            mapper.cpu_clock();
+            mapper.cpu_clock();
+        }
+        // Control $1B: horizontal mirroring, first bank fixed at $8000, 4KB CHR
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        assert_eq!(mapper.cpu_read(0xc000), Mapped::PrgRom(0));
+        assert_eq!(mapper.prg_bank, 0);
+    }
+
+    #[test]
+    fn reset_bit_clears_the_shift_register() {
+        let mut mapper = mapper();
+        write_serial(&mut mapper, 0x8000, 0x02);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+
+        // Two bits in, then a reset: the next five writes start afresh
+        mapper.cpu_write(0xa000, 1);
+        mapper.cpu_clock();
+        mapper.cpu_clock();
+        mapper.cpu_write(0xa000, 1);
+        mapper.cpu_clock();
+        mapper.cpu_clock();
+        mapper.cpu_write(0x8000, 0x80);
+        mapper.cpu_clock();
+        mapper.cpu_clock();
+        assert_eq!(mapper.control, 0x0e);
+
+        write_serial(&mut mapper, 0xa000, 0x03);
+        assert_eq!(mapper.chr_bank0, 0x03);
+    }
+
+    #[test]
+    fn back_to_back_writes_are_ignored() {
+        let mut mapper = mapper();
+        for bit in 0..5 {
+            mapper.cpu_write(0xe000, 0x01);
+            // The dummy write of a read-modify-write lands on the next cycle
+            mapper.cpu_clock();
+            mapper.cpu_write(0xe000, 0x00);
+            mapper.cpu_clock();
+            if bit < 4 {
+                mapper.cpu_clock();
+            }
+        }
+        assert_eq!(mapper.prg_bank, 0x1f);
+    }
+}
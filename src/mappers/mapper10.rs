@@ -0,0 +1,112 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::mappers::mapper9::ChrLatch;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// MMC4 (FxROM): switchable 16KB bank at $8000, last bank fixed at $C000,
+// 8KB PRG-RAM and the same CHR latches as MMC2
+#[derive(Debug)]
+pub struct Mapper10 {
+    prg_rom_banks: usize,
+    prg_bank: usize,
+    chr: ChrLatch,
+    mirroring: Mirroring,
+}
+
+impl Mapper10 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: (header.prg_rom_size / 0x4000).max(1),
+            prg_bank: 0,
+            chr: ChrLatch::new(false),
+            mirroring: Mirroring::Vertical,
+        }
+    }
+}
+
+impl Mapper for Mapper10 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x6000..=0x7fff => Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xbfff => Mapped::PrgRom((self.prg_bank * 0x4000) | (addr as usize & 0x3fff)),
+            0xc000..=0xffff => Mapped::PrgRom(((self.prg_rom_banks - 1) * 0x4000) | (addr as usize & 0x3fff)),
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x6000..=0x7fff => return Mapped::PrgRam(addr as usize & 0x1fff),
+            0xa000..=0xafff => self.prg_bank = (data & 0x0f) as usize % self.prg_rom_banks,
+            0xb000..=0xefff => self.chr.write(addr, data),
+            0xf000..=0xffff => {
+                self.mirroring = if data & 0x01 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        let offset = self.chr.offset(addr);
+        self.chr.fetched(addr);
+        Mapped::Chr(offset)
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr.offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let state = StateWriter::new()
+            .u8(self.prg_bank as u8)
+            .bool(self.mirroring == Mirroring::Horizontal);
+        self.chr.save_state(state).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_bank = r.u8()? as usize % self.prg_rom_banks;
+        self.mirroring = if r.bool()? { Mirroring::Horizontal } else { Mirroring::Vertical };
+        self.chr.load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_header;
+
+    // $FD banks 1 and 3, $FE banks 2 and 4, both latches start on $FE
+    fn mapper() -> Mapper10 {
+        let mut mapper = Mapper10::new(&test_header(8, 16, 10));
+        for (n, &addr) in [0xb000, 0xc000, 0xd000, 0xe000].iter().enumerate() {
+            mapper.cpu_write(addr, n as u8 + 1);
+        }
+        mapper
+    }
+
+    #[test]
+    fn both_latches_flip_on_any_row() {
+        let mut mapper = mapper();
+        // Unlike MMC2, the lower latch flips on $0FD8-$0FDF, after the fetch
+        assert_eq!(mapper.ppu_read(0x0fd9), Mapped::Chr(0x2fd9));
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x1000));
+        assert_eq!(mapper.ppu_read(0x0fef), Mapped::Chr(0x1fef));
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x2000));
+
+        assert_eq!(mapper.ppu_read(0x1fdc), Mapped::Chr(0x4fdc));
+        assert_eq!(mapper.ppu_read(0x1000), Mapped::Chr(0x3000));
+        assert_eq!(mapper.ppu_read(0x1fe8), Mapped::Chr(0x3fe8));
+        assert_eq!(mapper.ppu_read(0x1000), Mapped::Chr(0x4000));
+    }
+}
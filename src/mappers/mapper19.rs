@@ -0,0 +1,303 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// One wavetable channel is updated (and output) every 15 CPU cycles
+const CHANNEL_CYCLES: u8 = 15;
+
+// Output level of one step of a channel's (sample - 8) * volume
+const AUDIO_SCALE: f32 = 0.0025;
+
+const IRQ_COUNTER_MAX: u16 = 0x7fff;
+
+// Namco 163 (mapper 19)
+// 128 bytes of internal RAM hold both the wavetables and the channel
+// registers, which live at $40-$7F with channel 7 at the top
+#[derive(Debug)]
+pub struct Mapper19 {
+    prg_rom_banks: usize,
+    prg_banks: [u8; 3],
+    // $8000-$B800 pattern tables, $C000-$D800 nametables
+    chr_banks: [u8; 12],
+    // $E800 bits 6 and 7, keep each pattern table half on CHR-ROM
+    ciram_disabled: [bool; 2],
+    sound_disabled: bool,
+    // $F800: RAM address port and PRG-RAM write protect
+    address: u8,
+    ram: [u8; 0x80],
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+    channel_cycle: u8,
+    channel: usize,
+    output: f32,
+}
+
+impl Mapper19 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: (header.prg_rom_size / 0x2000).max(1),
+            prg_banks: [0; 3],
+            chr_banks: [0; 12],
+            ciram_disabled: [false; 2],
+            sound_disabled: false,
+            address: 0,
+            ram: [0; 0x80],
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            channel_cycle: 0,
+            channel: 7,
+            output: 0.0,
+        }
+    }
+
+    // Bank values $E0 and up select a page of CIRAM instead of CHR-ROM
+    fn chr_target(&self, addr: u16, bank: u8, ciram_allowed: bool) -> Mapped {
+        if bank >= 0xe0 && ciram_allowed {
+            Mapped::Ciram(((bank as usize & 0x01) << 10) | (addr as usize & 0x03ff))
+        }
+        else {
+            Mapped::Chr((bank as usize * 0x0400) | (addr as usize & 0x03ff))
+        }
+    }
+
+    fn pattern_target(&self, addr: u16) -> Mapped {
+        let slot = addr as usize >> 10;
+        self.chr_target(addr, self.chr_banks[slot], !self.ciram_disabled[slot >> 2])
+    }
+
+    fn nametable_target(&self, addr: u16) -> Mapped {
+        let quadrant = (addr as usize >> 10) & 0x03;
+        self.chr_target(addr, self.chr_banks[8 + quadrant], true)
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let window = (addr as usize - 0x6000) >> 11;
+        self.address & 0xf0 == 0x40 && self.address & (0x01 << window) == 0
+    }
+
+    fn data_port(&mut self) -> usize {
+        let addr = (self.address & 0x7f) as usize;
+        if self.address & 0x80 != 0 {
+            self.address = 0x80 | (self.address.wrapping_add(1) & 0x7f);
+        }
+        addr
+    }
+
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7f] >> 4) & 0x07) as usize + 1
+    }
+
+    // Advance one channel and make it the output, as the chip does in turn
+    fn clock_channel(&mut self) {
+        let base = 0x40 + self.channel * 8;
+        let regs = &self.ram[base..base + 8];
+
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0x03) as u32) << 16;
+        let length = 256 - (regs[4] & 0xfc) as u32;
+        let mut phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        phase = (phase + frequency) % (length << 16);
+
+        let sample_addr = (regs[6] as u32 + (phase >> 16)) as usize & 0xff;
+        let byte = self.ram[sample_addr >> 1];
+        let sample = if sample_addr & 0x01 == 0 { byte & 0x0f } else { byte >> 4 };
+        let volume = regs[7] & 0x0f;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+        self.output = (sample as f32 - 8.0) * volume as f32 * AUDIO_SCALE;
+
+        self.channel = if self.channel <= 8 - self.enabled_channels() { 7 } else { self.channel - 1 };
+    }
+}
+
+impl Mapper for Mapper19 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        let bank = match addr {
+            0x4800..=0x4fff => {
+                let addr = self.data_port();
+                return Mapped::Data(self.ram[addr]);
+            }
+            0x5000..=0x57ff => return Mapped::Data(self.irq_counter as u8),
+            0x5800..=0x5fff => return Mapped::Data((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7),
+            0x6000..=0x7fff => return Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xdfff => (self.prg_banks[(addr as usize - 0x8000) >> 13] & 0x3f) as usize,
+            0xe000..=0xffff => self.prg_rom_banks - 1,
+            _ => return Mapped::None,
+        };
+        Mapped::PrgRom((bank * 0x2000) | (addr as usize & 0x1fff))
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x4800..=0x4fff => {
+                let addr = self.data_port();
+                self.ram[addr] = data;
+            }
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | ((data & 0x7f) as u16) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7fff if self.prg_ram_writable(addr) => return Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xdfff => self.chr_banks[(addr as usize - 0x8000) >> 11] = data,
+            0xe000..=0xe7ff => {
+                self.prg_banks[0] = data & 0x3f;
+                self.sound_disabled = data & 0x40 != 0;
+            }
+            0xe800..=0xefff => {
+                self.prg_banks[1] = data & 0x3f;
+                self.ciram_disabled = [data & 0x40 != 0, data & 0x80 != 0];
+            }
+            0xf000..=0xf7ff => self.prg_banks[2] = data & 0x3f,
+            0xf800..=0xffff => self.address = data,
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        self.pattern_target(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        self.pattern_target(addr)
+    }
+
+    fn nametable_read(&mut self, addr: u16) -> Mapped {
+        self.nametable_target(addr)
+    }
+
+    fn nametable_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        self.nametable_target(addr)
+    }
+
+    // Only meaningful while all four nametables are in CIRAM, they're mapped above
+    fn mirroring(&self) -> Mirroring {
+        match [8, 9, 10, 11].map(|n| self.chr_banks[n] & 0x01) {
+            [0, 1, 0, 1] => Mirroring::Vertical,
+            [0, 0, 1, 1] => Mirroring::Horizontal,
+            [0, 0, 0, 0] => Mirroring::SingleScreenLower,
+            [1, 1, 1, 1] => Mirroring::SingleScreenUpper,
+            _ => Mirroring::FourScreen,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < IRQ_COUNTER_MAX {
+            self.irq_counter += 1;
+            if self.irq_counter == IRQ_COUNTER_MAX {
+                self.irq_pending = true;
+            }
+        }
+
+        self.channel_cycle += 1;
+        if self.channel_cycle >= CHANNEL_CYCLES {
+            self.channel_cycle = 0;
+            self.clock_channel();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio(&self) -> f32 {
+        if self.sound_disabled { 0.0 } else { self.output }
+    }
+
+    fn battery_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn battery_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .bytes(&self.prg_banks)
+            .bytes(&self.chr_banks)
+            .bool(self.ciram_disabled[0])
+            .bool(self.ciram_disabled[1])
+            .bool(self.sound_disabled)
+            .u8(self.address)
+            .bytes(&self.ram)
+            .u16(self.irq_counter)
+            .bool(self.irq_enabled)
+            .bool(self.irq_pending)
+            .u8(self.channel_cycle)
+            .u8(self.channel as u8)
+            .f64(self.output as f64)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_banks = r.array()?;
+        self.chr_banks = r.array()?;
+        self.ciram_disabled = [r.bool()?, r.bool()?];
+        self.sound_disabled = r.bool()?;
+        self.address = r.u8()?;
+        self.ram = r.array()?;
+        self.irq_counter = r.u16()? & IRQ_COUNTER_MAX;
+        self.irq_enabled = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.channel_cycle = r.u8()? % CHANNEL_CYCLES;
+        self.channel = (r.u8()? & 0x07) as usize;
+        self.output = r.f64()? as f32;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_header;
+
+    // 128KB PRG-ROM, 128KB CHR-ROM
+    fn mapper() -> Mapper19 {
+        Mapper19::new(&test_header(8, 16, 19))
+    }
+
+    #[test]
+    fn data_port_auto_increments_and_wraps() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0xf800, 0xfe);
+        for data in [0x11, 0x22, 0x33].iter() {
+            mapper.cpu_write(0x4800, *data);
+        }
+        assert_eq!(mapper.ram[0x7e], 0x11);
+        assert_eq!(mapper.ram[0x7f], 0x22);
+        assert_eq!(mapper.ram[0x00], 0x33);
+        assert_eq!(mapper.address, 0x81);
+
+        // Reads step the address too
+        mapper.cpu_write(0xf800, 0xfe);
+        assert_eq!(mapper.cpu_read(0x4800), Mapped::Data(0x11));
+        assert_eq!(mapper.cpu_read(0x4800), Mapped::Data(0x22));
+        assert_eq!(mapper.cpu_read(0x4800), Mapped::Data(0x33));
+    }
+
+    #[test]
+    fn data_port_holds_without_bit_7() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0xf800, 0x10);
+        mapper.cpu_write(0x4800, 0x11);
+        mapper.cpu_write(0x4800, 0x22);
+        assert_eq!(mapper.ram[0x10], 0x22);
+        assert_eq!(mapper.ram[0x11], 0x00);
+        assert_eq!(mapper.cpu_read(0x4800), Mapped::Data(0x22));
+        assert_eq!(mapper.address, 0x10);
+    }
+}
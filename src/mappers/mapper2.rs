@@ -0,0 +1,70 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// UxROM: switchable 16KB bank at $8000, last bank fixed at $C000
+#[derive(Debug)]
+pub struct Mapper2 {
+    prg_rom_banks: usize,
+    prg_bank: usize,
+    bus_conflicts: bool,
+    mirroring: Mirroring,
+}
+
+impl Mapper2 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: (header.prg_rom_size / 0x4000).max(1),
+            prg_bank: 0,
+            bus_conflicts: header.submapper == 2,
+            mirroring: header.mirroring,
+        }
+    }
+}
+
+impl Mapper for Mapper2 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x8000..=0xbfff => Mapped::PrgRom((self.prg_bank * 0x4000) | (addr as usize & 0x3fff)),
+            0xc000..=0xffff => Mapped::PrgRom(((self.prg_rom_banks - 1) * 0x4000) | (addr as usize & 0x3fff)),
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        if addr >= 0x8000 {
+            self.prg_bank = data as usize % self.prg_rom_banks;
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(addr as usize)
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(addr as usize)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new().u8(self.prg_bank as u8).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_bank = r.u8()? as usize % self.prg_rom_banks;
+        r.finish()
+    }
+}
@@ -0,0 +1,169 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::mappers::vrc;
+use crate::mappers::vrc::VrcIrq;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25)
+// The boards differ in which CPU address lines reach the chip's A0 and A1,
+// NES 2.0 submappers pin down the variant, otherwise both wirings are decoded
+#[derive(Debug)]
+pub struct Mapper21 {
+    vrc4: bool,
+    a0: u16,
+    a1: u16,
+    // VRC2a ignores the low bit of the CHR bank number
+    chr_shift: u8,
+    prg_rom_banks: usize,
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring_control: u8,
+    irq: VrcIrq,
+}
+
+impl Mapper21 {
+    pub fn new(header: &Header) -> Self {
+        let (vrc4, a0, a1) = match (header.mapper_id, header.submapper) {
+            // VRC4a, VRC4c
+            (21, 1) => (true, 0x02, 0x04),
+            (21, 2) => (true, 0x40, 0x80),
+            (21, _) => (true, 0x42, 0x84),
+            // VRC2a
+            (22, _) => (false, 0x02, 0x01),
+            // VRC4f, VRC4e, VRC2b
+            (23, 1) => (true, 0x01, 0x02),
+            (23, 2) => (true, 0x04, 0x08),
+            (23, 3) => (false, 0x01, 0x02),
+            (23, _) => (true, 0x05, 0x0a),
+            // VRC4b, VRC4d, VRC2c
+            (_, 1) => (true, 0x02, 0x01),
+            (_, 2) => (true, 0x08, 0x04),
+            (_, 3) => (false, 0x02, 0x01),
+            (_, _) => (true, 0x0a, 0x05),
+        };
+
+        Self {
+            vrc4,
+            a0,
+            a1,
+            chr_shift: if header.mapper_id == 22 { 1 } else { 0 },
+            prg_rom_banks: (header.prg_rom_size / 0x2000).max(2),
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring_control: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let second_last = self.prg_rom_banks - 2;
+        let bank = match (addr >> 13) & 0x03 {
+            0 => if self.prg_swap { second_last } else { self.prg_banks[0] as usize },
+            1 => self.prg_banks[1] as usize,
+            2 => if self.prg_swap { self.prg_banks[0] as usize } else { second_last },
+            _ => self.prg_rom_banks - 1,
+        };
+        (bank * 0x2000) | (addr as usize & 0x1fff)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = (self.chr_banks[addr as usize >> 10] >> self.chr_shift) as usize;
+        (bank * 0x0400) | (addr as usize & 0x03ff)
+    }
+
+    fn write_chr_bank(&mut self, reg: u16, data: u8) {
+        // $B000-$E003, two registers hold the low and high half of each bank
+        let index = ((((reg >> 12) - 0x0b) << 1) | ((reg >> 1) & 0x01)) as usize;
+        let bank = &mut self.chr_banks[index];
+        if reg & 0x01 == 0 {
+            *bank = (*bank & 0x01f0) | (data & 0x0f) as u16;
+        }
+        else {
+            let high = if self.vrc4 { data & 0x1f } else { data & 0x0f };
+            *bank = (*bank & 0x000f) | (high as u16) << 4;
+        }
+    }
+}
+
+impl Mapper for Mapper21 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x6000..=0x7fff => Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xffff => Mapped::PrgRom(self.prg_offset(addr)),
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x6000..=0x7fff => return Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xffff => {}
+            _ => return Mapped::None,
+        }
+
+        let reg = vrc::register(addr, self.a0, self.a1);
+        match reg {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1f,
+            0x9000..=0x9003 if !self.vrc4 => self.mirroring_control = data & 0x01,
+            0x9000..=0x9001 => self.mirroring_control = data & 0x03,
+            0x9002..=0x9003 => self.prg_swap = data & 0x02 != 0,
+            0xa000..=0xa003 => self.prg_banks[1] = data & 0x1f,
+            0xb000..=0xe003 => self.write_chr_bank(reg, data),
+            0xf000 if self.vrc4 => self.irq.write_latch_low(data),
+            0xf001 if self.vrc4 => self.irq.write_latch_high(data),
+            0xf002 if self.vrc4 => self.irq.write_control(data),
+            0xf003 if self.vrc4 => self.irq.acknowledge(),
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        vrc::mirroring(self.mirroring_control)
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new()
+            .bytes(&self.prg_banks)
+            .bool(self.prg_swap)
+            .u8(self.mirroring_control);
+        for bank in self.chr_banks {
+            state = state.u16(bank);
+        }
+        self.irq.save_state(state).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_banks = r.array()?;
+        self.prg_swap = r.bool()?;
+        self.mirroring_control = r.u8()?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = r.u16()?;
+        }
+        self.irq.load_state(&mut r)?;
+        r.finish()
+    }
+}
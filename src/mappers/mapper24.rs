@@ -0,0 +1,365 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::mappers::vrc;
+use crate::mappers::vrc::VrcIrq;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// Output level of one step of the 6 bit channel sum, about the APU pulse's
+const AUDIO_SCALE: f32 = 0.0098;
+
+// 16 step pulse with a 3 bit duty, or a constant level in digitized mode
+#[derive(Debug, Default)]
+struct Pulse {
+    volume: u8,
+    duty: u8,
+    digitized: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.digitized = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0f;
+            }
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = (self.step + 1) & 0x0f;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) { self.volume } else { 0 }
+    }
+
+    fn save_state(&self, state: StateWriter) -> StateWriter {
+        state
+            .u8(self.volume)
+            .u8(self.duty)
+            .bool(self.digitized)
+            .u16(self.period)
+            .bool(self.enabled)
+            .u16(self.timer)
+            .u8(self.step)
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.volume = r.u8()? & 0x0f;
+        self.duty = r.u8()? & 0x07;
+        self.digitized = r.bool()?;
+        self.period = r.u16()? & 0x0fff;
+        self.enabled = r.bool()?;
+        self.timer = r.u16()?;
+        self.step = r.u8()? & 0x0f;
+        Ok(())
+    }
+}
+
+// Adds the rate to an accumulator every other step, resetting after six adds
+#[derive(Debug, Default)]
+struct Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            }
+            else if self.step & 0x01 == 0 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, state: StateWriter) -> StateWriter {
+        state
+            .u8(self.rate)
+            .u16(self.period)
+            .bool(self.enabled)
+            .u16(self.timer)
+            .u8(self.step)
+            .u8(self.accumulator)
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.rate = r.u8()? & 0x3f;
+        self.period = r.u16()? & 0x0fff;
+        self.enabled = r.bool()?;
+        self.timer = r.u16()?;
+        self.step = r.u8()? % 14;
+        self.accumulator = r.u8()?;
+        Ok(())
+    }
+}
+
+// Konami VRC6 (mappers 24 and 26, the latter with A0 and A1 swapped)
+// Nametables sourced from CHR-ROM ($B003 bit 4) aren't supported
+#[derive(Debug)]
+pub struct Mapper24 {
+    a0: u16,
+    a1: u16,
+    prg_rom_banks: usize,
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003
+    banking: u8,
+    irq: VrcIrq,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    sawtooth: Sawtooth,
+    halt: bool,
+    frequency_shift: u8,
+}
+
+impl Mapper24 {
+    pub fn new(header: &Header) -> Self {
+        let (a0, a1) = if header.mapper_id == 26 { (0x02, 0x01) } else { (0x01, 0x02) };
+        Self {
+            a0,
+            a1,
+            prg_rom_banks: (header.prg_rom_size / 0x2000).max(1),
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            banking: 0,
+            irq: VrcIrq::new(),
+            pulse1: Pulse::default(),
+            pulse2: Pulse::default(),
+            sawtooth: Sawtooth::default(),
+            halt: false,
+            frequency_shift: 0,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking & 0x80 != 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let slot = addr as usize >> 10;
+        // 2KB banks take CHR A10 from the PPU
+        let (bank, size) = match self.banking & 0x03 {
+            0 => (self.chr_banks[slot], 0x0400),
+            1 => (self.chr_banks[slot >> 1] & 0xfe, 0x0800),
+            _ if slot < 4 => (self.chr_banks[slot], 0x0400),
+            _ => (self.chr_banks[4 + ((slot >> 1) & 0x01)] & 0xfe, 0x0800),
+        };
+        (bank as usize * 0x0400) | (addr as usize & (size - 1))
+    }
+}
+
+impl Mapper for Mapper24 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xbfff => Mapped::PrgRom(((self.prg_bank_16k & 0x0f) as usize * 0x4000) | (addr as usize & 0x3fff)),
+            0xc000..=0xdfff => Mapped::PrgRom(((self.prg_bank_8k & 0x1f) as usize * 0x2000) | (addr as usize & 0x1fff)),
+            0xe000..=0xffff => Mapped::PrgRom(((self.prg_rom_banks - 1) * 0x2000) | (addr as usize & 0x1fff)),
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => return Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xffff => {}
+            _ => return Mapped::None,
+        }
+
+        let reg = vrc::register(addr, self.a0, self.a1);
+        match reg {
+            0x8000..=0x8003 => self.prg_bank_16k = data,
+            0x9000..=0x9002 => self.pulse1.write(reg & 0x03, data),
+            0x9003 => {
+                self.halt = data & 0x01 != 0;
+                self.frequency_shift = if data & 0x04 != 0 { 8 } else if data & 0x02 != 0 { 4 } else { 0 };
+            }
+            0xa000..=0xa002 => self.pulse2.write(reg & 0x03, data),
+            0xb000..=0xb002 => self.sawtooth.write(reg & 0x03, data),
+            0xb003 => self.banking = data,
+            0xc000..=0xc003 => self.prg_bank_8k = data,
+            0xd000..=0xd003 => self.chr_banks[(reg & 0x03) as usize] = data,
+            0xe000..=0xe003 => self.chr_banks[4 + (reg & 0x03) as usize] = data,
+            0xf000 => self.irq.write_latch(data),
+            0xf001 => self.irq.write_control(data),
+            0xf002 => self.irq.acknowledge(),
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        vrc::mirroring(self.banking >> 2)
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+
+        if !self.halt {
+            self.pulse1.clock(self.frequency_shift);
+            self.pulse2.clock(self.frequency_shift);
+            self.sawtooth.clock(self.frequency_shift);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        sum as f32 * AUDIO_SCALE
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let state = StateWriter::new()
+            .u8(self.prg_bank_16k)
+            .u8(self.prg_bank_8k)
+            .bytes(&self.chr_banks)
+            .u8(self.banking)
+            .bool(self.halt)
+            .u8(self.frequency_shift);
+        let state = self.irq.save_state(state);
+        let state = self.pulse1.save_state(state);
+        let state = self.pulse2.save_state(state);
+        self.sawtooth.save_state(state).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_bank_16k = r.u8()?;
+        self.prg_bank_8k = r.u8()?;
+        self.chr_banks = r.array()?;
+        self.banking = r.u8()?;
+        self.halt = r.bool()?;
+        self.frequency_shift = match r.u8()? {
+            shift @ (0 | 4 | 8) => shift,
+            _ => return Err(InvalidState),
+        };
+        self.irq.load_state(&mut r)?;
+        self.pulse1.load_state(&mut r)?;
+        self.pulse2.load_state(&mut r)?;
+        self.sawtooth.load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pulse_is_high_for_duty_plus_one_of_16_steps() {
+        let mut pulse = Pulse::default();
+        pulse.write(0, 0x3a);
+        pulse.write(2, 0x80);
+        // A zero period steps on every clock
+        let levels: Vec<u8> = (0..32).map(|_| {
+            let level = pulse.output();
+            pulse.clock(0);
+            level
+        }).collect();
+        let expected: Vec<u8> = (0..32).map(|step| if step % 16 <= 3 { 10 } else { 0 }).collect();
+        assert_eq!(levels, expected);
+
+        // Digitized mode ignores the duty
+        pulse.write(0, 0x8a);
+        assert!((0..16).all(|_| {
+            pulse.clock(0);
+            pulse.output() == 10
+        }));
+    }
+
+    #[test]
+    fn sawtooth_accumulator_resets_after_six_adds() {
+        let mut sawtooth = Sawtooth::default();
+        sawtooth.write(0, 0x08);
+        sawtooth.write(2, 0x80);
+        let levels: Vec<u8> = (0..28).map(|_| {
+            sawtooth.clock(0);
+            sawtooth.output()
+        }).collect();
+        // The rate is added on every other step, the 14th clears it
+        let ramp = [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 0];
+        assert_eq!(levels, [ramp, ramp].concat());
+
+        // Disabling the channel clears the accumulator too
+        sawtooth.clock(0);
+        sawtooth.clock(0);
+        sawtooth.write(2, 0x00);
+        assert_eq!(sawtooth.output(), 0);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// CNROM: fixed PRG like NROM, switchable 8KB CHR bank
+#[derive(Debug)]
+pub struct Mapper3 {
+    prg_mask: u16,
+    chr_bank: usize,
+    bus_conflicts: bool,
+    mirroring: Mirroring,
+}
+
+impl Mapper3 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_mask: if header.prg_rom_size > 0x4000 { 0x7fff } else { 0x3fff },
+            chr_bank: 0,
+            bus_conflicts: header.submapper == 2,
+            mirroring: header.mirroring,
+        }
+    }
+}
+
+impl Mapper for Mapper3 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        if addr >= 0x8000 {
+            return Mapped::PrgRom((addr & self.prg_mask) as usize);
+        }
+        Mapped::None
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        if addr >= 0x8000 {
+            self.chr_bank = data as usize;
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr((self.chr_bank * 0x2000) | (addr as usize & 0x1fff))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr((self.chr_bank * 0x2000) | (addr as usize & 0x1fff))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new().u8(self.chr_bank as u8).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.chr_bank = r.u8()? as usize;
+        r.finish()
+    }
+}
@@ -0,0 +1,324 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// A12 has to stay low for this many CPU cycles before a rise clocks the counter
+const A12_FILTER_CYCLES: u64 = 3;
+
+// MMC3 (TxROM)
+#[derive(Debug)]
+pub struct Mapper4 {
+    prg_rom_banks: usize,
+    four_screen: bool,
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    prg_ram_protected: bool,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    cycle: u64,
+    a12_high: bool,
+    a12_low_since: u64,
+}
+
+impl Mapper4 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: (header.prg_rom_size / 0x2000).max(2),
+            four_screen: header.mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: Mirroring::Vertical,
+            prg_ram_enabled: true,
+            prg_ram_protected: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            cycle: 0,
+            a12_high: false,
+            a12_low_since: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let second_last = self.prg_rom_banks - 2;
+        let last = self.prg_rom_banks - 1;
+        let swap = self.bank_select & 0x40 != 0;
+
+        let bank = match (addr >> 13) & 0x03 {
+            0 => if swap { second_last } else { self.registers[6] as usize },
+            1 => self.registers[7] as usize,
+            2 => if swap { self.registers[6] as usize } else { second_last },
+            _ => last,
+        };
+        ((bank % self.prg_rom_banks) * 0x2000) | (addr as usize & 0x1fff)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // A12 inversion swaps the 2KB and 1KB halves
+        let addr = if self.bank_select & 0x80 != 0 { addr ^ 0x1000 } else { addr } as usize;
+
+        let bank = match addr >> 10 {
+            0 => self.registers[0] & 0xfe,
+            1 => self.registers[0] | 0x01,
+            2 => self.registers[1] & 0xfe,
+            3 => self.registers[1] | 0x01,
+            n => self.registers[n - 2],
+        };
+        (bank as usize * 0x0400) | (addr & 0x03ff)
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        }
+        else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mapper4 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled => Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xffff => Mapped::PrgRom(self.prg_offset(addr)),
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled && !self.prg_ram_protected => {
+                return Mapped::PrgRam(addr as usize & 0x1fff);
+            }
+            0x8000..=0xffff => {}
+            _ => return Mapped::None,
+        }
+
+        match (addr & 0xe000, addr & 0x01) {
+            (0x8000, 0) => self.bank_select = data,
+            (0x8000, _) => self.registers[(self.bank_select & 0x07) as usize] = data,
+            (0xa000, 0) => {
+                self.mirroring = if data & 0x01 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            (0xa000, _) => {
+                self.prg_ram_enabled = data & 0x80 != 0;
+                self.prg_ram_protected = data & 0x40 != 0;
+            }
+            (0xc000, 0) => self.irq_latch = data,
+            (0xc000, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            _ => self.irq_enabled = true,
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.four_screen {
+            Mirroring::FourScreen
+        }
+        else {
+            self.mirroring
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+
+    // The scanline counter is clocked by filtered rising edges on PPU A12
+    fn ppu_address(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12_high {
+            if self.cycle - self.a12_low_since >= A12_FILTER_CYCLES {
+                self.clock_irq_counter();
+            }
+        }
+        else if !a12 && self.a12_high {
+            self.a12_low_since = self.cycle;
+        }
+        self.a12_high = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .u8(self.bank_select)
+            .bytes(&self.registers)
+            .bool(self.mirroring == Mirroring::Horizontal)
+            .bool(self.prg_ram_enabled)
+            .bool(self.prg_ram_protected)
+            .u8(self.irq_latch)
+            .u8(self.irq_counter)
+            .bool(self.irq_reload)
+            .bool(self.irq_enabled)
+            .bool(self.irq_pending)
+            .bool(self.a12_high)
+            .u64(self.cycle)
+            .u64(self.a12_low_since)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.bank_select = r.u8()?;
+        self.registers = r.array()?;
+        self.mirroring = if r.bool()? { Mirroring::Horizontal } else { Mirroring::Vertical };
+        self.prg_ram_enabled = r.bool()?;
+        self.prg_ram_protected = r.bool()?;
+        self.irq_latch = r.u8()?;
+        self.irq_counter = r.u8()?;
+        self.irq_reload = r.bool()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.a12_high = r.bool()?;
+        self.cycle = r.u64()?;
+        self.a12_low_since = r.u64()?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cartridge::Cartridge;
+    use crate::nes::BusDevice;
+    use crate::ppu::Ppu;
+
+    const HEADER: &[u8; 16] = b"NES\x1a\x02\x01\x40\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+    fn mapper_with_irq(latch: u8) -> Mapper4 {
+        let mut mapper = Mapper4::new(&Header::new(HEADER).unwrap());
+        mapper.cpu_write(0xc000, latch);
+        mapper.cpu_write(0xc001, 0);
+        mapper.cpu_write(0xe001, 0);
+        mapper
+    }
+
+    // A12 low for `low_cycles` CPU cycles, then back high
+    fn a12_pulse(mapper: &mut Mapper4, low_cycles: u64) {
+        mapper.ppu_address(0x0000);
+        for _ in 0..low_cycles {
+            mapper.cpu_clock();
+        }
+        mapper.ppu_address(0x1000);
+    }
+
+    #[test]
+    fn irq_after_latch_plus_one_rises() {
+        let mut mapper = mapper_with_irq(2);
+        for _ in 0..4 {
+            mapper.cpu_clock();
+        }
+
+        // Reload to 2, then 1, then 0
+        for _ in 0..2 {
+            a12_pulse(&mut mapper, 10);
+            assert!(!mapper.irq());
+        }
+        a12_pulse(&mut mapper, 10);
+        assert!(mapper.irq());
+
+        // $E000 acknowledges and disables
+        mapper.cpu_write(0xe000, 0);
+        assert!(!mapper.irq());
+        for _ in 0..3 {
+            a12_pulse(&mut mapper, 10);
+        }
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn short_a12_pulses_are_filtered() {
+        let mut mapper = mapper_with_irq(0);
+        mapper.ppu_address(0x1000);
+        for _ in 0..4 {
+            mapper.cpu_clock();
+        }
+
+        // A latch of 0 fires on every clock once reloaded
+        for _ in 0..8 {
+            a12_pulse(&mut mapper, 1);
+        }
+        assert!(mapper.irq_reload);
+        assert!(!mapper.irq());
+
+        a12_pulse(&mut mapper, A12_FILTER_CYCLES);
+        assert!(mapper.irq());
+    }
+
+    // Run a PPU for a frame from the pre-render line, returning the dot the IRQ fires on
+    fn ppu_irq_dot(latch: u8, ctrl: u8, mask: u8) -> Option<u32> {
+        let mut file = HEADER.to_vec();
+        file.resize(16 + 0x8000 + 0x2000, 0);
+        let cartridge = Rc::new(RefCell::new(Cartridge::from_bytes(&file).unwrap()));
+        cartridge.borrow_mut().write(0xc000, latch);
+        cartridge.borrow_mut().write(0xc001, 0);
+        cartridge.borrow_mut().write(0xe001, 0);
+
+        let mut ppu = Ppu::new();
+        ppu.insert(cartridge.clone());
+        ppu.write(0x2000, ctrl);
+        ppu.write(0x2001, mask);
+
+        (0..341 * 262).find(|dot| {
+            ppu.clock();
+            if dot % 3 == 0 {
+                cartridge.borrow_mut().cpu_clock();
+            }
+            cartridge.borrow().irq()
+        })
+    }
+
+    #[test]
+    fn ppu_rendering_clocks_the_counter_once_a_line() {
+        // Background at $0000 and sprites at $1000, so A12 rises once a line.
+        // The pre-render line reloads the counter, lines 0-4 count it down
+        let dot = ppu_irq_dot(5, 0x08, 0x18).unwrap();
+        assert_eq!(dot / 341, 5);
+        // Sprite pattern fetches start at dot 257
+        assert!((257..321).contains(&(dot % 341)), "IRQ at dot {}", dot % 341);
+    }
+
+    #[test]
+    fn no_fetches_with_rendering_disabled() {
+        // With the background at $1000 any background fetch after the idle
+        // sprite fetch window would clock the counter
+        assert_eq!(ppu_irq_dot(0, 0x10, 0x00), None);
+    }
+}
@@ -0,0 +1,766 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+use crate::apu::Envelope;
+use crate::apu::LengthCounter;
+use crate::apu::DUTY_TABLE;
+
+// The PPU has stopped reading once it's been quiet for this many CPU cycles
+const IDLE_CYCLES: u8 = 3;
+
+// Pattern fetches the PPU makes on a line: two for each of the 32 background
+// tiles, two for each of the eight sprites, then the first two tiles of the next line
+const SPRITE_FETCHES_START: u8 = 64;
+const SPRITE_FETCHES_END: u8 = 80;
+const LINE_FETCHES: u8 = 84;
+
+// The expansion audio frame sequencer runs at 240Hz
+const AUDIO_FRAME_CYCLES: u16 = 7457;
+
+// Pulse channel, the same as the APU's without the sweep unit
+#[derive(Debug, Default)]
+struct Pulse {
+    duty: u8,
+    duty_pos: u8,
+    timer: u16,
+    timer_period: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Pulse {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | (((data & 0x07) as u16) << 8);
+                self.length.load(data >> 3);
+                self.duty_pos = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || DUTY_TABLE[self.duty as usize][self.duty_pos as usize] == 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    fn save_state(&self, state: StateWriter) -> StateWriter {
+        let state = state
+            .u8(self.duty)
+            .u8(self.duty_pos)
+            .u16(self.timer)
+            .u16(self.timer_period);
+        let state = self.length.save_state(state);
+        self.envelope.save_state(state)
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.duty = r.u8()? & 0x03;
+        self.duty_pos = r.u8()? & 0x07;
+        self.timer = r.u16()?;
+        self.timer_period = r.u16()? & 0x07ff;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)
+    }
+}
+
+// MMC5 (ExROM)
+// PPU fetches are watched to find scanlines and to tell sprite
+// pattern fetches from background ones
+#[derive(Debug)]
+pub struct Mapper5 {
+    prg_ram_size: usize,
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    exram: [u8; 0x400],
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attrib: u8,
+    // $5113-$5117
+    prg_banks: [u8; 5],
+    // $5120-$5127 (A) and $5128-$512B (B)
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    last_chr_b: bool,
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    irq_target: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+    // Snooped from $2000 and $2001
+    sprite_8x16: bool,
+    rendering: bool,
+    in_frame: bool,
+    scanline: u8,
+    idle_cycles: u8,
+    last_nametable_addr: Option<u16>,
+    nametable_matches: u8,
+    // Pattern fetches since the start of the line, which tell which tile
+    // a nametable fetch is for and when the sprites are being fetched
+    pattern_fetches: u8,
+    tile_fetched: bool,
+    ext_attrib: u8,
+    split_tile: Option<u8>,
+    split_row: u8,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm_read_mode: bool,
+    pcm: u8,
+    audio_cycle: u16,
+    odd_cycle: bool,
+}
+
+impl Mapper5 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_ram_size: header.prg_ram_size + header.prg_nvram_size,
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            exram: [0; 0x400],
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attrib: 0,
+            prg_banks: [0, 0, 0, 0, 0xff],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_chr_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_target: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+            sprite_8x16: false,
+            rendering: false,
+            in_frame: false,
+            scanline: 0,
+            idle_cycles: 0,
+            last_nametable_addr: None,
+            nametable_matches: 0,
+            pattern_fetches: 0,
+            tile_fetched: false,
+            ext_attrib: 0,
+            split_tile: None,
+            split_row: 0,
+            pulse1: Pulse::default(),
+            pulse2: Pulse::default(),
+            pcm_read_mode: false,
+            pcm: 0,
+            audio_cycle: 0,
+            odd_cycle: false,
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x02, 0x01]
+    }
+
+    // Bit 2 of a RAM bank is the chip select. ETROM has an 8KB chip on each,
+    // boards with one chip leave the upper four banks open bus
+    fn prg_ram_target(&self, bank: u8, offset: usize) -> Mapped {
+        let bank = match (self.prg_ram_size, bank & 0x07) {
+            (0x4000, bank) => (bank >> 2) as usize,
+            (0x2000, bank) | (0x8000, bank) if bank >= 4 => return Mapped::None,
+            (_, bank) => bank as usize,
+        };
+        Mapped::PrgRam((bank * 0x2000) | offset)
+    }
+
+    fn prg_target(&self, addr: u16) -> Mapped {
+        if addr < 0x8000 {
+            return self.prg_ram_target(self.prg_banks[0], addr as usize & 0x1fff);
+        }
+
+        // Register ($5113 + n) and window size
+        let (reg, size) = match (self.prg_mode, addr) {
+            (0, _) => (4, 0x8000),
+            (1, 0x8000..=0xbfff) => (2, 0x4000),
+            (1, _) => (4, 0x4000),
+            (2, 0x8000..=0xbfff) => (2, 0x4000),
+            (2, 0xc000..=0xdfff) => (3, 0x2000),
+            (2, _) => (4, 0x2000),
+            _ => (1 + ((addr as usize - 0x8000) >> 13), 0x2000),
+        };
+
+        let value = self.prg_banks[reg];
+        if reg != 4 && value & 0x80 == 0 {
+            // RAM banks are 8KB, a 16KB window takes an even/odd pair of them
+            let bank = if size == 0x4000 { (value & !1) | ((addr >> 13) & 1) as u8 } else { value };
+            return self.prg_ram_target(bank, addr as usize & 0x1fff);
+        }
+
+        let bank = (value & 0x7f) as usize & !(size / 0x2000 - 1);
+        Mapped::PrgRom((bank * 0x2000) | (addr as usize & (size - 1)))
+    }
+
+    fn chr_offset(&self, addr: u16, set_a: bool) -> usize {
+        // Bank size in 1KB units, each window uses the last register of its group
+        let size = 8 >> self.chr_mode;
+        let index = ((addr as usize >> 10) & 0x07) | (size - 1);
+        let bank = if set_a { self.chr_banks[index] } else { self.chr_banks[8 | (index & 0x03)] } as usize;
+        (bank * size * 0x0400) | (addr as usize & (size * 0x0400 - 1))
+    }
+
+    fn split_enabled(&self) -> bool {
+        self.split_control & 0x80 != 0 && self.exram_mode < 2
+    }
+
+    fn in_split(&self, column: u8) -> bool {
+        let count = self.split_control & 0x1f;
+        if self.split_control & 0x40 != 0 { column >= count } else { column < count }
+    }
+
+    // Three reads of the same nametable address in a row mark the end of a line
+    fn detect_scanline(&mut self, addr: u16) {
+        if self.last_nametable_addr == Some(addr) {
+            self.nametable_matches = self.nametable_matches.saturating_add(1);
+            if self.nametable_matches == 2 && self.rendering {
+                self.start_scanline();
+            }
+        }
+        else {
+            self.last_nametable_addr = Some(addr);
+            self.nametable_matches = 0;
+        }
+    }
+
+    fn start_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_target {
+                self.irq_pending = true;
+            }
+        }
+        else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        }
+        self.pattern_fetches = 0;
+        self.tile_fetched = false;
+    }
+
+    fn leave_frame(&mut self) {
+        self.in_frame = false;
+        self.last_nametable_addr = None;
+    }
+
+    // A nametable fetch that isn't an attribute. Background tiles are the first
+    // of four fetches, the tile is told by the pattern fetches made before it
+    fn fetch_tile(&mut self, addr: u16) -> Option<Mapped> {
+        let (column, line) = match self.pattern_fetches {
+            n if n < SPRITE_FETCHES_START => (n / 2 + 2, self.scanline),
+            // The last two tiles fetched on a line are the first two of the next
+            n if (SPRITE_FETCHES_END..LINE_FETCHES).contains(&n) => ((n - SPRITE_FETCHES_END) / 2, self.scanline.wrapping_add(1)),
+            // Garbage fetches between the sprites, and the dummy fetches ending the line
+            _ => return None,
+        };
+        self.tile_fetched = true;
+        self.split_tile = None;
+
+        if self.split_enabled() && self.in_split(column) {
+            let row = ((self.split_scroll as usize + line as usize) % 240) as u8;
+            let tile = self.exram[((row as usize >> 3) << 5) | (column as usize & 0x1f)];
+            self.split_tile = Some(tile);
+            self.split_row = row;
+            self.ext_attrib = self.exram[0x3c0 | ((row as usize >> 5) << 3) | ((column as usize & 0x1f) >> 2)];
+            let shift = ((row >> 2) & 0x04) | (column & 0x02);
+            self.ext_attrib = ((self.ext_attrib >> shift) & 0x03) << 6;
+            return Some(Mapped::Data(tile));
+        }
+
+        if self.exram_mode == 1 {
+            self.ext_attrib = self.exram[addr as usize & 0x03ff];
+        }
+        None
+    }
+
+    fn nametable_target(&self, addr: u16) -> Mapped {
+        let quadrant = (addr >> 10) & 0x03;
+        let offset = addr as usize & 0x03ff;
+        match (self.nametable_mapping >> (quadrant * 2)) & 0x03 {
+            0 => Mapped::Ciram(offset),
+            1 => Mapped::Ciram(0x0400 | offset),
+            2 if self.exram_mode < 2 => Mapped::Data(self.exram[offset]),
+            2 => Mapped::Data(0),
+            _ if offset >= 0x03c0 => Mapped::Data(Self::replicate(self.fill_attrib)),
+            _ => Mapped::Data(self.fill_tile),
+        }
+    }
+
+    // Copy a palette number into all four quadrants of an attribute byte
+    fn replicate(palette: u8) -> u8 {
+        (palette & 0x03) * 0x55
+    }
+
+    fn clock_audio(&mut self) {
+        self.odd_cycle = !self.odd_cycle;
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.audio_cycle += 1;
+        if self.audio_cycle >= AUDIO_FRAME_CYCLES {
+            self.audio_cycle = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.envelope.clock();
+                pulse.length.clock();
+            }
+        }
+    }
+}
+
+impl Mapper for Mapper5 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x5010 => Mapped::Data(0),
+            0x5015 => Mapped::Data(self.pulse1.length.active() as u8 | (self.pulse2.length.active() as u8) << 1),
+            0x5204 => {
+                let status = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                Mapped::Data(status)
+            }
+            0x5205 => Mapped::Data((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => Mapped::Data(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            0x5c00..=0x5fff if self.exram_mode >= 2 => Mapped::Data(self.exram[addr as usize & 0x03ff]),
+            0x6000..=0xffff => {
+                // Fetching the NMI vector means the PPU has reached vblank
+                if addr == 0xfffa || addr == 0xfffb {
+                    self.leave_frame();
+                }
+                self.prg_target(addr)
+            }
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x2000..=0x3fff => match addr & 0x0007 {
+                0 => self.sprite_8x16 = data & 0x20 != 0,
+                1 => {
+                    self.rendering = data & 0x18 != 0;
+                    if !self.rendering {
+                        self.leave_frame();
+                    }
+                }
+                _ => {}
+            },
+            0x5000..=0x5003 => self.pulse1.write(addr & 0x03, data),
+            0x5004..=0x5007 => self.pulse2.write(addr & 0x03, data),
+            // Read mode would need the byte on the data bus, which the cartridge
+            // doesn't hand us, so it just stops $5011 writes and never raises an IRQ
+            0x5010 => self.pcm_read_mode = data & 0x01 != 0,
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            0x5015 => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+            }
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.prg_ram_protect[0] = data & 0x03,
+            0x5103 => self.prg_ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attrib = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113] = data,
+            0x5120..=0x512b => {
+                self.chr_banks[addr as usize - 0x5120] = data as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_b = addr >= 0x5128;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_target = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5c00..=0x5fff => {
+                // As a nametable ExRAM can only be written while rendering
+                match self.exram_mode {
+                    0 | 1 => self.exram[addr as usize & 0x03ff] = if self.in_frame { data } else { 0 },
+                    2 => self.exram[addr as usize & 0x03ff] = data,
+                    _ => {}
+                }
+            }
+            0x6000..=0xdfff if self.prg_ram_writable() => {
+                if let Mapped::PrgRam(offset) = self.prg_target(addr) {
+                    return Mapped::PrgRam(offset);
+                }
+            }
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        self.idle_cycles = 0;
+        self.last_nametable_addr = None;
+        self.tile_fetched = false;
+
+        let sprite = self.in_frame && (SPRITE_FETCHES_START..SPRITE_FETCHES_END).contains(&self.pattern_fetches);
+        self.pattern_fetches = self.pattern_fetches.saturating_add(1);
+
+        if self.in_frame && !sprite {
+            if let Some(tile) = self.split_tile {
+                let offset = ((tile as usize) << 4) | (addr as usize & 0x08) | (self.split_row as usize & 0x07);
+                return Mapped::Chr((self.split_bank as usize * 0x1000) | offset);
+            }
+            if self.exram_mode == 1 {
+                let bank = (self.ext_attrib & 0x3f) as usize | (self.chr_upper as usize) << 6;
+                return Mapped::Chr((bank * 0x1000) | (addr as usize & 0x0fff));
+            }
+        }
+
+        // With 8x16 sprites the background gets its own banks, outside
+        // rendering the set written last is used
+        let set_a = !self.sprite_8x16 || sprite || (!self.in_frame && !self.last_chr_b);
+        Mapped::Chr(self.chr_offset(addr, set_a))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr, !self.sprite_8x16 || !self.last_chr_b))
+    }
+
+    fn nametable_read(&mut self, addr: u16) -> Mapped {
+        self.idle_cycles = 0;
+        self.detect_scanline(addr);
+
+        if !self.in_frame {
+            return self.nametable_target(addr);
+        }
+
+        // The fetch straight after a tile fetch is its attribute, unless it's the
+        // same address again: the line's first tile is fetched more than once
+        if self.tile_fetched && self.nametable_matches == 0 {
+            self.tile_fetched = false;
+            if self.split_tile.is_some() || self.exram_mode == 1 {
+                return Mapped::Data(Self::replicate(self.ext_attrib >> 6));
+            }
+            return self.nametable_target(addr);
+        }
+
+        match self.fetch_tile(addr) {
+            Some(mapped) => mapped,
+            None => self.nametable_target(addr),
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) -> Mapped {
+        let quadrant = (addr >> 10) & 0x03;
+        match (self.nametable_mapping >> (quadrant * 2)) & 0x03 {
+            0 | 1 => self.nametable_target(addr),
+            2 => {
+                if self.exram_mode < 2 {
+                    self.exram[addr as usize & 0x03ff] = data;
+                }
+                Mapped::None
+            }
+            _ => Mapped::None,
+        }
+    }
+
+    // ExRAM and fill mode have no equivalent, the nametables are mapped above
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x00 => Mirroring::SingleScreenLower,
+            0x55 => Mirroring::SingleScreenUpper,
+            0x44 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.idle_cycles = self.idle_cycles.saturating_add(1);
+        if self.idle_cycles >= IDLE_CYCLES {
+            self.leave_frame();
+        }
+        self.clock_audio();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn audio(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+        pulse_out + self.pcm as f32 / 255.0 * 0.4
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new()
+            .u8(self.prg_mode)
+            .u8(self.chr_mode)
+            .bytes(&self.prg_ram_protect)
+            .u8(self.exram_mode)
+            .bytes(&self.exram)
+            .u8(self.nametable_mapping)
+            .u8(self.fill_tile)
+            .u8(self.fill_attrib)
+            .bytes(&self.prg_banks);
+        for bank in self.chr_banks {
+            state = state.u16(bank);
+        }
+        let state = state
+            .u8(self.chr_upper)
+            .bool(self.last_chr_b)
+            .u8(self.split_control)
+            .u8(self.split_scroll)
+            .u8(self.split_bank)
+            .u8(self.irq_target)
+            .bool(self.irq_enabled)
+            .bool(self.irq_pending)
+            .u8(self.multiplicand)
+            .u8(self.multiplier)
+            .bool(self.sprite_8x16)
+            .bool(self.rendering)
+            .bool(self.in_frame)
+            .u8(self.scanline)
+            .u8(self.idle_cycles)
+            .bool(self.last_nametable_addr.is_some())
+            .u16(self.last_nametable_addr.unwrap_or(0))
+            .u8(self.nametable_matches)
+            .u8(self.pattern_fetches)
+            .bool(self.tile_fetched)
+            .u8(self.ext_attrib)
+            .bool(self.split_tile.is_some())
+            .u8(self.split_tile.unwrap_or(0))
+            .u8(self.split_row)
+            .bool(self.pcm_read_mode)
+            .u8(self.pcm)
+            .u16(self.audio_cycle)
+            .bool(self.odd_cycle);
+        let state = self.pulse1.save_state(state);
+        self.pulse2.save_state(state).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_mode = r.u8()? & 0x03;
+        self.chr_mode = r.u8()? & 0x03;
+        self.prg_ram_protect = r.array()?;
+        self.exram_mode = r.u8()?;
+        self.exram = r.array()?;
+        self.nametable_mapping = r.u8()?;
+        self.fill_tile = r.u8()?;
+        self.fill_attrib = r.u8()?;
+        self.prg_banks = r.array()?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = r.u16()?;
+        }
+        self.chr_upper = r.u8()?;
+        self.last_chr_b = r.bool()?;
+        self.split_control = r.u8()?;
+        self.split_scroll = r.u8()?;
+        self.split_bank = r.u8()?;
+        self.irq_target = r.u8()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.multiplicand = r.u8()?;
+        self.multiplier = r.u8()?;
+        self.sprite_8x16 = r.bool()?;
+        self.rendering = r.bool()?;
+        self.in_frame = r.bool()?;
+        self.scanline = r.u8()?;
+        self.idle_cycles = r.u8()?;
+        let nametable_read = r.bool()?;
+        let last_nametable_addr = r.u16()?;
+        self.last_nametable_addr = if nametable_read { Some(last_nametable_addr) } else { None };
+        self.nametable_matches = r.u8()?;
+        self.pattern_fetches = r.u8()?;
+        self.tile_fetched = r.bool()?;
+        self.ext_attrib = r.u8()?;
+        let split = r.bool()?;
+        let split_tile = r.u8()?;
+        self.split_tile = if split { Some(split_tile) } else { None };
+        self.split_row = r.u8()?;
+        self.pcm_read_mode = r.bool()?;
+        self.pcm = r.u8()?;
+        self.audio_cycle = r.u16()? % AUDIO_FRAME_CYCLES;
+        self.odd_cycle = r.bool()?;
+        self.pulse1.load_state(&mut r)?;
+        self.pulse2.load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cartridge::Cartridge;
+    use crate::nes::BusDevice;
+    use crate::ppu::Ppu;
+
+    // Palette entries 1-3 of the first background and sprite palettes
+    const BACKGROUND: [u8; 3] = [0x16, 0x1a, 0x12];
+    const SPRITES: [u8; 3] = [0x28, 0x2a, 0x21];
+    const RED: (u8, u8, u8) = (152, 34, 32);
+    const BLUE: (u8, u8, u8) = (48, 50, 236);
+    const GREEN: (u8, u8, u8) = (76, 208, 32);
+
+    // Every pixel of 8KB CHR bank n has the value n, so the colour on screen
+    // tells which bank the fetch for it selected
+    fn cartridge() -> Rc<RefCell<Cartridge>> {
+        let mut file = b"NES\x1a\x01\x04\x50\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        for bank in 0..4u8 {
+            for row in 0..0x2000 {
+                let plane = if row & 0x08 == 0 { bank & 0x01 } else { bank >> 1 };
+                file.push(if plane != 0 { 0xff } else { 0x00 });
+            }
+        }
+        file.splice(16..16, vec![0; 0x4000]);
+        Rc::new(RefCell::new(Cartridge::from_bytes(&file).unwrap()))
+    }
+
+    // The CPU writes go to both chips, the MMC5 snoops the PPU registers
+    fn write(ppu: &mut Ppu, cartridge: &Rc<RefCell<Cartridge>>, addr: u16, data: u8) {
+        ppu.write(addr, data);
+        cartridge.borrow_mut().write(addr, data);
+    }
+
+    #[test]
+    fn fetches_pick_chr_sets_through_a_frame() {
+        let cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        ppu.insert(cartridge.clone());
+
+        // 8KB CHR banks: sprites from set A (bank 2), the background from set B (bank 1)
+        write(&mut ppu, &cartridge, 0x5101, 0x00);
+        write(&mut ppu, &cartridge, 0x5127, 0x02);
+        write(&mut ppu, &cartridge, 0x512b, 0x01);
+        // The leftmost four columns are a split from 4KB bank 6, inside CHR bank 3
+        write(&mut ppu, &cartridge, 0x5200, 0x84);
+        write(&mut ppu, &cartridge, 0x5202, 0x06);
+
+        write(&mut ppu, &cartridge, 0x2006, 0x3f);
+        write(&mut ppu, &cartridge, 0x2006, 0x00);
+        for &colour in [0x0f].iter().chain(&BACKGROUND).chain(&[0x0f; 13]).chain(&SPRITES) {
+            write(&mut ppu, &cartridge, 0x2007, colour);
+        }
+
+        // Eight 8x16 sprites on lines 10-25, every 32 pixels, the rest off screen
+        write(&mut ppu, &cartridge, 0x2003, 0x00);
+        for n in 0..64 {
+            let sprite = if n < 8 { [9, 0x00, 0x00, n * 32] } else { [0xff; 4] };
+            for &data in sprite.iter() {
+                write(&mut ppu, &cartridge, 0x2004, data);
+            }
+        }
+
+        write(&mut ppu, &cartridge, 0x2000, 0x20);
+        write(&mut ppu, &cartridge, 0x2001, 0x1e);
+
+        let mut dot = 0;
+        while !ppu.take_frame_complete() {
+            ppu.clock();
+            if dot % 3 == 0 {
+                cartridge.borrow_mut().cpu_clock();
+            }
+            dot += 1;
+        }
+
+        let frame = ppu.frame();
+        for y in 0..240 {
+            for x in 0..256 {
+                let expected = if (10..26).contains(&y) && x % 32 < 8 {
+                    GREEN
+                }
+                // Line 0 starts on the pre-render line, before the MMC5 sees a frame
+                else if x < 32 && (y > 0 || x >= 16) {
+                    BLUE
+                }
+                else {
+                    RED
+                };
+                assert_eq!(frame[y * 256 + x], expected, "pixel {}, {}", x, y);
+            }
+        }
+    }
+
+    // NES 2.0 header with 64 << `shift` bytes of PRG-RAM
+    fn mapper_with_prg_ram(shift: u8) -> Mapper5 {
+        let mut header = *b"NES\x1a\x01\x01\x50\x08\x00\x00\x00\x00\x00\x00\x00\x00";
+        header[10] = shift;
+        Mapper5::new(&Header::new(&header).unwrap())
+    }
+
+    #[test]
+    fn prg_ram_bank_bit_2_is_the_chip_select() {
+        // EKROM, one 8KB chip
+        let mut mapper = mapper_with_prg_ram(7);
+        mapper.cpu_write(0x5113, 0x03);
+        assert_eq!(mapper.cpu_read(0x6000), Mapped::PrgRam(0x6000));
+        mapper.cpu_write(0x5113, 0x04);
+        assert_eq!(mapper.cpu_read(0x6000), Mapped::None);
+
+        // ETROM, an 8KB chip on each select
+        let mut mapper = mapper_with_prg_ram(8);
+        mapper.cpu_write(0x5113, 0x03);
+        assert_eq!(mapper.cpu_read(0x6123), Mapped::PrgRam(0x0123));
+        mapper.cpu_write(0x5113, 0x05);
+        assert_eq!(mapper.cpu_read(0x6123), Mapped::PrgRam(0x2123));
+
+        // 64KB, every bank
+        let mut mapper = mapper_with_prg_ram(10);
+        mapper.cpu_write(0x5113, 0x07);
+        assert_eq!(mapper.cpu_read(0x6000), Mapped::PrgRam(0xe000));
+    }
+
+    #[test]
+    fn prg_ram_in_a_16kb_window_spans_two_banks() {
+        let mut mapper = mapper_with_prg_ram(10);
+        for &mode in [1, 2].iter() {
+            mapper.cpu_write(0x5100, mode);
+            // The low bit of the bank number is ignored
+            mapper.cpu_write(0x5115, 0x03);
+            assert_eq!(mapper.cpu_read(0x8123), Mapped::PrgRam(0x4123));
+            assert_eq!(mapper.cpu_read(0xa123), Mapped::PrgRam(0x6123));
+        }
+
+        // The 8KB windows of mode 3 use the bank as written
+        mapper.cpu_write(0x5100, 3);
+        mapper.cpu_write(0x5114, 0x03);
+        mapper.cpu_write(0x5115, 0x03);
+        assert_eq!(mapper.cpu_read(0x8123), Mapped::PrgRam(0x6123));
+        assert_eq!(mapper.cpu_read(0xa123), Mapped::PrgRam(0x6123));
+    }
+}
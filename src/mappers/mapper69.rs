@@ -0,0 +1,456 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// Tone, noise and envelope counters tick once every 16 CPU cycles
+const AUDIO_CLOCK_DIVIDER: u8 = 16;
+
+// Output level of a channel at full volume
+const AUDIO_VOLUME: f32 = 0.12;
+
+// Square channel of the 5B, a YM2149 variant
+#[derive(Debug, Default)]
+struct Tone {
+    period: u16,
+    counter: u16,
+    output: bool,
+}
+
+impl Tone {
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) {
+            self.counter = 0;
+            self.output = !self.output;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Noise {
+    period: u8,
+    counter: u8,
+    lfsr: u32,
+}
+
+impl Noise {
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) {
+            self.counter = 0;
+            // 17 bit LFSR, taps at bits 0 and 3
+            let bit = (self.lfsr ^ (self.lfsr >> 3)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (bit << 16);
+        }
+    }
+
+    fn output(&self) -> bool {
+        self.lfsr & 0x01 != 0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Envelope {
+    period: u16,
+    counter: u16,
+    shape: u8,
+    step: u8,
+    attack: bool,
+    holding: bool,
+}
+
+impl Envelope {
+    fn restart(&mut self, shape: u8) {
+        self.shape = shape & 0x0f;
+        self.counter = 0;
+        self.step = 0;
+        self.attack = shape & 0x04 != 0;
+        self.holding = false;
+    }
+
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter < self.period.max(1) {
+            return;
+        }
+        self.counter = 0;
+
+        if self.holding {
+            return;
+        }
+
+        self.step += 1;
+        if self.step < 16 {
+            return;
+        }
+        self.step = 0;
+
+        let (continues, alternate, hold) = (self.shape & 0x08 != 0, self.shape & 0x02 != 0, self.shape & 0x01 != 0);
+        if !continues {
+            self.attack = false;
+            self.holding = true;
+        }
+        else {
+            if alternate {
+                self.attack = !self.attack;
+            }
+            self.holding = hold;
+        }
+    }
+
+    fn level(&self) -> u8 {
+        match (self.holding, self.attack) {
+            (true, true) => 15,
+            (true, false) => 0,
+            (false, true) => self.step,
+            (false, false) => 15 - self.step,
+        }
+    }
+}
+
+// Sunsoft 5B expansion audio: three square channels with a shared noise
+// generator and envelope, on a logarithmic (3dB a step) volume scale
+#[derive(Debug)]
+struct Sunsoft5b {
+    select: u8,
+    tones: [Tone; 3],
+    noise: Noise,
+    envelope: Envelope,
+    mixer: u8,
+    volumes: [u8; 3],
+    divider: u8,
+    levels: [f32; 16],
+}
+
+impl Sunsoft5b {
+    fn new() -> Self {
+        let mut levels = [0.0; 16];
+        for (n, level) in levels.iter_mut().enumerate().skip(1) {
+            *level = 10f32.powf(-3.0 * (15 - n) as f32 / 20.0);
+        }
+
+        Self {
+            select: 0,
+            tones: Default::default(),
+            noise: Noise {
+                period: 0,
+                counter: 0,
+                lfsr: 1,
+            },
+            envelope: Envelope::default(),
+            mixer: 0xff,
+            volumes: [0; 3],
+            divider: 0,
+            levels,
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        match self.select {
+            0x00..=0x05 => {
+                let tone = &mut self.tones[self.select as usize >> 1];
+                if self.select & 0x01 == 0 {
+                    tone.period = (tone.period & 0x0f00) | data as u16;
+                }
+                else {
+                    tone.period = (tone.period & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                }
+            }
+            0x06 => self.noise.period = data & 0x1f,
+            0x07 => self.mixer = data,
+            0x08..=0x0a => self.volumes[self.select as usize - 0x08] = data & 0x1f,
+            0x0b => self.envelope.period = (self.envelope.period & 0xff00) | data as u16,
+            0x0c => self.envelope.period = (self.envelope.period & 0x00ff) | (data as u16) << 8,
+            0x0d => self.envelope.restart(data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < AUDIO_CLOCK_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        for tone in self.tones.iter_mut() {
+            tone.clock();
+        }
+        self.noise.clock();
+        self.envelope.clock();
+    }
+
+    fn output(&self) -> f32 {
+        let mut output = 0.0;
+        for (n, tone) in self.tones.iter().enumerate() {
+            let tone_on = tone.output || self.mixer & (0x01 << n) != 0;
+            let noise_on = self.noise.output() || self.mixer & (0x08 << n) != 0;
+            if !(tone_on && noise_on) {
+                continue;
+            }
+
+            let volume = self.volumes[n];
+            let level = if volume & 0x10 != 0 { self.envelope.level() } else { volume & 0x0f };
+            output += self.levels[level as usize];
+        }
+        output * AUDIO_VOLUME
+    }
+
+    fn save_state(&self, mut state: StateWriter) -> StateWriter {
+        state = state.u8(self.select);
+        for tone in self.tones.iter() {
+            state = state.u16(tone.period).u16(tone.counter).bool(tone.output);
+        }
+        state
+            .u8(self.noise.period)
+            .u8(self.noise.counter)
+            .u32(self.noise.lfsr)
+            .u16(self.envelope.period)
+            .u16(self.envelope.counter)
+            .u8(self.envelope.shape)
+            .u8(self.envelope.step)
+            .bool(self.envelope.attack)
+            .bool(self.envelope.holding)
+            .u8(self.mixer)
+            .bytes(&self.volumes)
+            .u8(self.divider)
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.select = r.u8()?;
+        for tone in self.tones.iter_mut() {
+            tone.period = r.u16()? & 0x0fff;
+            tone.counter = r.u16()?;
+            tone.output = r.bool()?;
+        }
+        self.noise.period = r.u8()? & 0x1f;
+        self.noise.counter = r.u8()?;
+        self.noise.lfsr = r.u32()? & 0x1ffff;
+        self.envelope.period = r.u16()?;
+        self.envelope.counter = r.u16()?;
+        self.envelope.shape = r.u8()? & 0x0f;
+        self.envelope.step = r.u8()? & 0x0f;
+        self.envelope.attack = r.bool()?;
+        self.envelope.holding = r.bool()?;
+        self.mixer = r.u8()?;
+        self.volumes = r.array()?;
+        self.divider = r.u8()? % AUDIO_CLOCK_DIVIDER;
+        Ok(())
+    }
+}
+
+// Sunsoft FME-7 and 5B (mapper 69)
+// Registers are reached through a command register at $8000 and a parameter at $A000
+#[derive(Debug)]
+pub struct Mapper69 {
+    prg_rom_banks: usize,
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000, $8000, $A000 and $C000
+    prg_banks: [u8; 4],
+    mirroring_control: u8,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5b,
+}
+
+impl Mapper69 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: (header.prg_rom_size / 0x2000).max(1),
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring_control: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5b::new(),
+        }
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x00..=0x07 => self.chr_banks[self.command as usize] = data,
+            0x08..=0x0b => self.prg_banks[self.command as usize - 0x08] = data,
+            0x0c => self.mirroring_control = data & 0x03,
+            0x0d => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x0e => self.irq_counter = (self.irq_counter & 0xff00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | (data as u16) << 8,
+        }
+    }
+
+    // $6000 is ROM, RAM or open bus depending on bits 6 and 7 of its bank register
+    fn ram_window(&self, addr: u16) -> Mapped {
+        let bank = self.prg_banks[0];
+        let offset = ((bank & 0x3f) as usize * 0x2000) | (addr as usize & 0x1fff);
+        match (bank & 0x40 != 0, bank & 0x80 != 0) {
+            (false, _) => Mapped::PrgRom(offset),
+            (true, true) => Mapped::PrgRam(offset),
+            (true, false) => Mapped::None,
+        }
+    }
+}
+
+impl Mapper for Mapper69 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        let bank = match addr {
+            0x6000..=0x7fff => return self.ram_window(addr),
+            0x8000..=0xdfff => (self.prg_banks[1 + ((addr as usize - 0x8000) >> 13)] & 0x3f) as usize,
+            0xe000..=0xffff => self.prg_rom_banks - 1,
+            _ => return Mapped::None,
+        };
+        Mapped::PrgRom((bank * 0x2000) | (addr as usize & 0x1fff))
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x6000..=0x7fff => {
+                if let Mapped::PrgRam(offset) = self.ram_window(addr) {
+                    return Mapped::PrgRam(offset);
+                }
+            }
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xdfff => self.audio.select = data,
+            0xe000..=0xffff => self.audio.write(data),
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr((self.chr_banks[addr as usize >> 10] as usize * 0x0400) | (addr as usize & 0x03ff))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr((self.chr_banks[addr as usize >> 10] as usize * 0x0400) | (addr as usize & 0x03ff))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring_control {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    // The counter decrements every cycle, firing as it wraps from $0000 to $FFFF
+    fn cpu_clock(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xffff && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let state = StateWriter::new()
+            .u8(self.command)
+            .bytes(&self.chr_banks)
+            .bytes(&self.prg_banks)
+            .u8(self.mirroring_control)
+            .bool(self.irq_enabled)
+            .bool(self.irq_counter_enabled)
+            .u16(self.irq_counter)
+            .bool(self.irq_pending);
+        self.audio.save_state(state).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.command = r.u8()?;
+        self.chr_banks = r.array()?;
+        self.prg_banks = r.array()?;
+        self.mirroring_control = r.u8()? & 0x03;
+        self.irq_enabled = r.bool()?;
+        self.irq_counter_enabled = r.bool()?;
+        self.irq_counter = r.u16()?;
+        self.irq_pending = r.bool()?;
+        self.audio.load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_header;
+
+    // 128KB PRG-ROM, 128KB CHR-ROM
+    fn mapper() -> Mapper69 {
+        Mapper69::new(&test_header(8, 16, 69))
+    }
+
+    fn command(mapper: &mut Mapper69, command: u8, parameter: u8) {
+        mapper.cpu_write(0x8000, command);
+        mapper.cpu_write(0xa000, parameter);
+    }
+
+    #[test]
+    fn irq_fires_as_the_counter_wraps() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0x0e, 0x02);
+        command(&mut mapper, 0x0f, 0x00);
+        command(&mut mapper, 0x0d, 0x81);
+
+        // $0001, $0000, then the wrap to $FFFF
+        for _ in 0..2 {
+            mapper.cpu_clock();
+            assert!(!mapper.irq());
+        }
+        mapper.cpu_clock();
+        assert!(mapper.irq());
+
+        // Any write to the control register acknowledges it
+        command(&mut mapper, 0x0d, 0x81);
+        assert!(!mapper.irq());
+        mapper.cpu_clock();
+        assert_eq!(mapper.irq_counter, 0xfffe);
+    }
+
+    #[test]
+    fn counter_and_irq_enable_are_separate() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0x0e, 0x00);
+        command(&mut mapper, 0x0f, 0x00);
+
+        // Counting with the IRQ disabled wraps silently
+        command(&mut mapper, 0x0d, 0x80);
+        mapper.cpu_clock();
+        assert_eq!(mapper.irq_counter, 0xffff);
+        assert!(!mapper.irq());
+
+        // The IRQ enabled without counting never fires
+        command(&mut mapper, 0x0e, 0x00);
+        command(&mut mapper, 0x0f, 0x00);
+        command(&mut mapper, 0x0d, 0x01);
+        for _ in 0..10 {
+            mapper.cpu_clock();
+        }
+        assert_eq!(mapper.irq_counter, 0x0000);
+        assert!(!mapper.irq());
+    }
+}
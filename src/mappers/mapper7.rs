@@ -0,0 +1,74 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// AxROM: switchable 32KB bank at $8000, bit 4 picks the single screen nametable
+#[derive(Debug)]
+pub struct Mapper7 {
+    prg_rom_banks: usize,
+    prg_bank: usize,
+    bus_conflicts: bool,
+    mirroring: Mirroring,
+}
+
+impl Mapper7 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: (header.prg_rom_size / 0x8000).max(1),
+            prg_bank: 0,
+            bus_conflicts: header.submapper == 2,
+            mirroring: Mirroring::SingleScreenLower,
+        }
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        if addr >= 0x8000 {
+            return Mapped::PrgRom((self.prg_bank * 0x8000) | (addr as usize & 0x7fff));
+        }
+        Mapped::None
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        if addr >= 0x8000 {
+            self.prg_bank = (data & 0x07) as usize % self.prg_rom_banks;
+            self.mirroring = if data & 0x10 == 0 { Mirroring::SingleScreenLower } else { Mirroring::SingleScreenUpper };
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(addr as usize)
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(addr as usize)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .u8(self.prg_bank as u8)
+            .bool(self.mirroring == Mirroring::SingleScreenUpper)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_bank = r.u8()? as usize % self.prg_rom_banks;
+        self.mirroring = if r.bool()? { Mirroring::SingleScreenUpper } else { Mirroring::SingleScreenLower };
+        r.finish()
+    }
+}
@@ -0,0 +1,531 @@
+use std::f64::consts::TAU;
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::mappers::vrc;
+use crate::mappers::vrc::VrcIrq;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// The synth runs at 3.58MHz / 72, one sample every 36 CPU cycles
+const FM_CLOCK_DIVIDER: u8 = 36;
+const FM_SAMPLE_RATE: f64 = 49_716.0;
+
+// Output level of a channel at full scale
+const FM_VOLUME: f32 = 0.12;
+
+// Attenuation (dB) the envelope runs over, anything past it is silent
+const ENVELOPE_RANGE: f64 = 48.0;
+
+// Seconds for rate 4 to cover the envelope range, each step of 4 halves it
+const ATTACK_TIME: f64 = 0.71;
+const DECAY_TIME: f64 = 9.82;
+
+const AM_RATE: f64 = 3.7;
+const AM_DEPTH: f64 = 4.8;
+const VIBRATO_RATE: f64 = 6.4;
+const VIBRATO_DEPTH: f64 = 0.004;
+
+const MULTIPLIERS: [f64; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+// Attenuation at block 7 for the top four F-number bits, 6dB less per octave below
+const KEY_SCALE_TABLE: [f64; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25,
+    36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+
+// Share of the key scale attenuation applied for KSL 0-3 (0, 1.5, 3, 6 dB/octave)
+const KEY_SCALE_LEVELS: [f64; 4] = [0.0, 0.25, 0.5, 1.0];
+
+// The built in instruments 1-15, instrument 0 is the custom patch in $00-$07
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+    [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+    [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+    [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+    [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// One operator's settings, decoded from a patch
+struct OperatorPatch {
+    am: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: u8,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    fn new(patch: &[u8; 8], carrier: bool) -> Self {
+        let n = carrier as usize;
+        Self {
+            am: patch[n] & 0x80 != 0,
+            vibrato: patch[n] & 0x40 != 0,
+            sustained: patch[n] & 0x20 != 0,
+            key_scale_rate: patch[n] & 0x10 != 0,
+            multiplier: patch[n] & 0x0f,
+            key_scale_level: if carrier { patch[3] >> 6 } else { patch[2] >> 6 },
+            rectified: patch[3] & if carrier { 0x10 } else { 0x08 } != 0,
+            attack: patch[4 + n] >> 4,
+            decay: patch[4 + n] & 0x0f,
+            sustain_level: patch[6 + n] >> 4,
+            release: patch[6 + n] & 0x0f,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Operator {
+    phase: f64,
+    // Envelope attenuation in dB
+    envelope: f64,
+    state: EnvelopeState,
+}
+
+impl Operator {
+    fn new() -> Self {
+        Self {
+            phase: 0.0,
+            envelope: ENVELOPE_RANGE,
+            state: EnvelopeState::Release,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        self.state = EnvelopeState::Release;
+    }
+
+    // Rate 0 stops the envelope, the rest are 4R plus the key scale offset
+    fn rate(rate: u8, key_scale: u8) -> u8 {
+        if rate == 0 { 0 } else { (rate * 4 + key_scale).min(63) }
+    }
+
+    fn time(base: f64, rate: u8) -> f64 {
+        base / 2f64.powi(rate as i32 / 4 - 1) / (1.0 + (rate & 0x03) as f64 / 4.0)
+    }
+
+    fn decay_step(rate: u8) -> f64 {
+        if rate == 0 { 0.0 } else { ENVELOPE_RANGE / Self::time(DECAY_TIME, rate) / FM_SAMPLE_RATE }
+    }
+
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, channel_sustain: bool) {
+        let key_scale = if patch.key_scale_rate { key_scale } else { key_scale >> 2 };
+
+        match self.state {
+            EnvelopeState::Attack => {
+                let rate = Self::rate(patch.attack, key_scale);
+                if rate >= 60 {
+                    self.envelope = 0.0;
+                }
+                else if rate > 0 {
+                    // Exponential approach to full volume
+                    let k = 4.0 / (Self::time(ATTACK_TIME, rate) * FM_SAMPLE_RATE);
+                    self.envelope -= (self.envelope + 1.5) * k;
+                }
+
+                if self.envelope <= 0.0 {
+                    self.envelope = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                let sustain = patch.sustain_level as f64 * 3.0;
+                self.envelope += Self::decay_step(Self::rate(patch.decay, key_scale));
+                if self.envelope >= sustain {
+                    self.envelope = sustain;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain => {
+                // Percussive tones keep decaying at the release rate while held
+                if !patch.sustained {
+                    self.envelope += Self::decay_step(Self::rate(patch.release, key_scale));
+                }
+            }
+            EnvelopeState::Release => {
+                let release = if channel_sustain { 5 } else if patch.sustained { patch.release } else { 7 };
+                self.envelope += Self::decay_step(Self::rate(release, key_scale));
+            }
+        }
+        self.envelope = self.envelope.min(ENVELOPE_RANGE);
+    }
+
+    // Advance the phase and return the output for a phase offset (in cycles)
+    fn output(&mut self, patch: &OperatorPatch, step: f64, vibrato: f64, attenuation: f64, modulation: f64) -> f64 {
+        let step = step * MULTIPLIERS[patch.multiplier as usize];
+        self.phase = (self.phase + if patch.vibrato { step * vibrato } else { step }).fract();
+
+        let attenuation = attenuation + self.envelope;
+        if attenuation >= ENVELOPE_RANGE {
+            return 0.0;
+        }
+
+        let wave = (TAU * (self.phase + modulation)).sin();
+        let wave = if patch.rectified && wave < 0.0 { 0.0 } else { wave };
+        wave * 10f64.powf(-attenuation / 20.0)
+    }
+
+    fn save_state(&self, state: StateWriter) -> StateWriter {
+        state
+            .f64(self.phase)
+            .f64(self.envelope)
+            .u8(self.state as u8)
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.phase = r.f64()?;
+        self.envelope = r.f64()?;
+        self.state = match r.u8()? {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::Decay,
+            2 => EnvelopeState::Sustain,
+            3 => EnvelopeState::Release,
+            _ => return Err(InvalidState),
+        };
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Channel {
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [f64; 2],
+    output: f64,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+            output: 0.0,
+        }
+    }
+}
+
+// Six channel, two operator FM synth derived from the YM2413 (OPLL)
+#[derive(Debug)]
+struct Opll {
+    select: u8,
+    registers: [u8; 0x40],
+    channels: [Channel; 6],
+    divider: u8,
+    am_phase: f64,
+    vibrato_phase: f64,
+}
+
+impl Opll {
+    fn new() -> Self {
+        Self {
+            select: 0,
+            registers: [0; 0x40],
+            channels: std::array::from_fn(|_| Channel::new()),
+            divider: 0,
+            am_phase: 0.0,
+            vibrato_phase: 0.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write(&mut self, data: u8) {
+        let reg = self.select as usize & 0x3f;
+        let old = self.registers[reg];
+        self.registers[reg] = data;
+
+        if (0x20..0x26).contains(&reg) {
+            let channel = &mut self.channels[reg & 0x07];
+            match (old & 0x10 != 0, data & 0x10 != 0) {
+                (false, true) => {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                }
+                (true, false) => {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn patch(&self, channel: usize) -> [u8; 8] {
+        match self.registers[0x30 + channel] >> 4 {
+            0 => {
+                let mut patch = [0; 8];
+                patch.copy_from_slice(&self.registers[0..8]);
+                patch
+            }
+            n => PATCHES[n as usize - 1],
+        }
+    }
+
+    fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < FM_CLOCK_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        self.am_phase = (self.am_phase + AM_RATE / FM_SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_RATE / FM_SAMPLE_RATE).fract();
+        let am = (1.0 - (TAU * self.am_phase).cos()) * 0.5 * AM_DEPTH;
+        let vibrato = 1.0 + (TAU * self.vibrato_phase).sin() * VIBRATO_DEPTH;
+
+        for n in 0..6 {
+            let patch = self.patch(n);
+            let modulator_patch = OperatorPatch::new(&patch, false);
+            let carrier_patch = OperatorPatch::new(&patch, true);
+
+            let fnum = self.registers[0x10 + n] as u16 | ((self.registers[0x20 + n] & 0x01) as u16) << 8;
+            let block = (self.registers[0x20 + n] >> 1) & 0x07;
+            let channel_sustain = self.registers[0x20 + n] & 0x20 != 0;
+            let volume = self.registers[0x30 + n] & 0x0f;
+            let key_scale = (block << 1) | (fnum >> 8) as u8;
+            let key_scale_base = (KEY_SCALE_TABLE[fnum as usize >> 5] - 6.0 * (7 - block) as f64).max(0.0);
+            // Cycles per sample at multiplier 1
+            let step = fnum as f64 * (1 << block) as f64 / (1 << 19) as f64;
+
+            let channel = &mut self.channels[n];
+            channel.modulator.clock_envelope(&modulator_patch, key_scale, channel_sustain);
+            channel.carrier.clock_envelope(&carrier_patch, key_scale, channel_sustain);
+
+            let feedback_level = patch[3] & 0x07;
+            let feedback = if feedback_level == 0 {
+                0.0
+            }
+            else {
+                (channel.feedback[0] + channel.feedback[1]) / 2.0 * (1 << (feedback_level - 1)) as f64 / 32.0
+            };
+
+            let attenuation = (patch[2] & 0x3f) as f64 * 0.75
+                + key_scale_base * KEY_SCALE_LEVELS[modulator_patch.key_scale_level as usize]
+                + if modulator_patch.am { am } else { 0.0 };
+            let modulator = channel.modulator.output(&modulator_patch, step, vibrato, attenuation, feedback);
+            channel.feedback = [channel.feedback[1], modulator];
+
+            let attenuation = volume as f64 * 3.0
+                + key_scale_base * KEY_SCALE_LEVELS[carrier_patch.key_scale_level as usize]
+                + if carrier_patch.am { am } else { 0.0 };
+            channel.output = channel.carrier.output(&carrier_patch, step, vibrato, attenuation, modulator * 2.0);
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.channels.iter().map(|channel| channel.output as f32).sum::<f32>() * FM_VOLUME
+    }
+
+    fn save_state(&self, mut state: StateWriter) -> StateWriter {
+        state = state
+            .u8(self.select)
+            .bytes(&self.registers)
+            .u8(self.divider)
+            .f64(self.am_phase)
+            .f64(self.vibrato_phase);
+        for channel in self.channels.iter() {
+            state = channel.modulator.save_state(state);
+            state = channel.carrier.save_state(state)
+                .f64(channel.feedback[0])
+                .f64(channel.feedback[1])
+                .f64(channel.output);
+        }
+        state
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.select = r.u8()?;
+        self.registers = r.array()?;
+        self.divider = r.u8()? % FM_CLOCK_DIVIDER;
+        self.am_phase = r.f64()?;
+        self.vibrato_phase = r.f64()?;
+        for channel in self.channels.iter_mut() {
+            channel.modulator.load_state(r)?;
+            channel.carrier.load_state(r)?;
+            channel.feedback = [r.f64()?, r.f64()?];
+            channel.output = r.f64()?;
+        }
+        Ok(())
+    }
+}
+
+// Konami VRC7 (mapper 85)
+// VRC7a decodes A0 from CPU A4, VRC7b from A3
+#[derive(Debug)]
+pub struct Mapper85 {
+    a0: u16,
+    prg_rom_banks: usize,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    // $E000: silence, PRG-RAM enable and mirroring
+    control: u8,
+    irq: VrcIrq,
+    opll: Opll,
+}
+
+impl Mapper85 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            a0: match header.submapper {
+                1 => 0x08,
+                2 => 0x10,
+                _ => 0x18,
+            },
+            prg_rom_banks: (header.prg_rom_size / 0x2000).max(1),
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            opll: Opll::new(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x40 != 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        (self.chr_banks[addr as usize >> 10] as usize * 0x0400) | (addr as usize & 0x03ff)
+    }
+}
+
+impl Mapper for Mapper85 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        let bank = match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => return Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0x9fff => self.prg_banks[0] as usize,
+            0xa000..=0xbfff => self.prg_banks[1] as usize,
+            0xc000..=0xdfff => self.prg_banks[2] as usize,
+            0xe000..=0xffff => self.prg_rom_banks - 1,
+            _ => return Mapped::None,
+        };
+        Mapped::PrgRom((bank * 0x2000) | (addr as usize & 0x1fff))
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => return Mapped::PrgRam(addr as usize & 0x1fff),
+            0x8000..=0xffff => {}
+            _ => return Mapped::None,
+        }
+
+        // The audio ports are decoded from A4 and A5 whatever the board
+        match addr & 0xf030 {
+            0x9010 => {
+                self.opll.select = data;
+                return Mapped::None;
+            }
+            0x9030 => {
+                if self.control & 0x80 == 0 {
+                    self.opll.write(data);
+                }
+                return Mapped::None;
+            }
+            _ => {}
+        }
+
+        let reg = (addr & 0xf000) | (addr & self.a0 != 0) as u16;
+        match reg {
+            0x8000 => self.prg_banks[0] = data & 0x3f,
+            0x8001 => self.prg_banks[1] = data & 0x3f,
+            0x9000 => self.prg_banks[2] = data & 0x3f,
+            0xa000..=0xd001 => {
+                let index = ((((reg >> 12) - 0x0a) << 1) | (reg & 0x01)) as usize;
+                self.chr_banks[index] = data;
+            }
+            0xe000 => {
+                self.control = data;
+                if data & 0x80 != 0 {
+                    self.opll.reset();
+                }
+            }
+            0xe001 => self.irq.write_latch(data),
+            0xf000 => self.irq.write_control(data),
+            0xf001 => self.irq.acknowledge(),
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr_offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        vrc::mirroring(self.control)
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+        if self.control & 0x80 == 0 {
+            self.opll.clock();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio(&self) -> f32 {
+        if self.control & 0x80 != 0 { 0.0 } else { self.opll.output() }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let state = StateWriter::new()
+            .bytes(&self.prg_banks)
+            .bytes(&self.chr_banks)
+            .u8(self.control);
+        let state = self.irq.save_state(state);
+        self.opll.save_state(state).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_banks = r.array()?;
+        self.chr_banks = r.array()?;
+        self.control = r.u8()?;
+        self.irq.load_state(&mut r)?;
+        self.opll.load_state(&mut r)?;
+        r.finish()
+    }
+}
@@ -0,0 +1,184 @@
+use crate::mappers::Mapper;
+use crate::mappers::Mapped;
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Header;
+use crate::cartridge::Mirroring;
+
+// CHR banking shared by MMC2 and MMC4. Each 4KB half has an $FD and an $FE
+// bank, picked by a latch the PPU flips when it fetches tile $FD or $FE
+#[derive(Debug)]
+pub struct ChrLatch {
+    // MMC2 only flips the lower latch on the first row of the tile
+    exact_low_trigger: bool,
+    banks: [[u8; 2]; 2],
+    latches: [usize; 2],
+}
+
+impl ChrLatch {
+    pub fn new(exact_low_trigger: bool) -> Self {
+        Self {
+            exact_low_trigger,
+            banks: [[0; 2]; 2],
+            latches: [1, 1],
+        }
+    }
+
+    // $B000-$EFFF
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let reg = ((addr >> 12) - 0x0b) as usize;
+        self.banks[reg >> 1][reg & 0x01] = data & 0x1f;
+    }
+
+    pub fn offset(&self, addr: u16) -> usize {
+        let half = (addr as usize >> 12) & 0x01;
+        let bank = self.banks[half][self.latches[half]] as usize;
+        (bank * 0x1000) | (addr as usize & 0x0fff)
+    }
+
+    // The latch changes after the fetch, so the tile itself uses the old bank
+    pub fn fetched(&mut self, addr: u16) {
+        let half = (addr as usize >> 12) & 0x01;
+        let tile = addr & 0x0ff8;
+        if half == 0 && self.exact_low_trigger && addr & 0x07 != 0 {
+            return;
+        }
+
+        match tile {
+            0x0fd8 => self.latches[half] = 0,
+            0x0fe8 => self.latches[half] = 1,
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, state: StateWriter) -> StateWriter {
+        state
+            .bytes(&self.banks[0])
+            .bytes(&self.banks[1])
+            .u8(self.latches[0] as u8)
+            .u8(self.latches[1] as u8)
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.banks = [r.array()?, r.array()?];
+        self.latches = [(r.u8()? & 0x01) as usize, (r.u8()? & 0x01) as usize];
+        Ok(())
+    }
+}
+
+// MMC2 (PxROM): switchable 8KB bank at $8000, the last three fixed
+#[derive(Debug)]
+pub struct Mapper9 {
+    prg_rom_banks: usize,
+    prg_bank: usize,
+    chr: ChrLatch,
+    mirroring: Mirroring,
+}
+
+impl Mapper9 {
+    pub fn new(header: &Header) -> Self {
+        Self {
+            prg_rom_banks: (header.prg_rom_size / 0x2000).max(4),
+            prg_bank: 0,
+            chr: ChrLatch::new(true),
+            mirroring: Mirroring::Vertical,
+        }
+    }
+}
+
+impl Mapper for Mapper9 {
+    fn cpu_read(&mut self, addr: u16) -> Mapped {
+        match addr {
+            0x8000..=0x9fff => Mapped::PrgRom((self.prg_bank * 0x2000) | (addr as usize & 0x1fff)),
+            0xa000..=0xffff => {
+                let bank = self.prg_rom_banks - 4 + ((addr as usize - 0x8000) >> 13);
+                Mapped::PrgRom((bank * 0x2000) | (addr as usize & 0x1fff))
+            }
+            _ => Mapped::None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> Mapped {
+        match addr {
+            0xa000..=0xafff => self.prg_bank = (data & 0x0f) as usize % self.prg_rom_banks,
+            0xb000..=0xefff => self.chr.write(addr, data),
+            0xf000..=0xffff => {
+                self.mirroring = if data & 0x01 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            _ => {}
+        }
+        Mapped::None
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Mapped {
+        let offset = self.chr.offset(addr);
+        self.chr.fetched(addr);
+        Mapped::Chr(offset)
+    }
+
+    fn ppu_write(&mut self, addr: u16, _data: u8) -> Mapped {
+        Mapped::Chr(self.chr.offset(addr))
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let state = StateWriter::new()
+            .u8(self.prg_bank as u8)
+            .bool(self.mirroring == Mirroring::Horizontal);
+        self.chr.save_state(state).finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), InvalidState> {
+        let mut r = StateReader::new(state);
+        self.prg_bank = r.u8()? as usize % self.prg_rom_banks;
+        self.mirroring = if r.bool()? { Mirroring::Horizontal } else { Mirroring::Vertical };
+        self.chr.load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_header;
+
+    // $FD banks 1 and 3, $FE banks 2 and 4, both latches start on $FE
+    fn mapper() -> Mapper9 {
+        let mut mapper = Mapper9::new(&test_header(8, 16, 9));
+        for (n, &addr) in [0xb000, 0xc000, 0xd000, 0xe000].iter().enumerate() {
+            mapper.cpu_write(addr, n as u8 + 1);
+        }
+        mapper
+    }
+
+    #[test]
+    fn lower_latch_flips_only_on_the_first_row() {
+        let mut mapper = mapper();
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x2000));
+        mapper.ppu_read(0x0fd9);
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x2000));
+
+        // The fetch that flips the latch still comes from the old bank
+        assert_eq!(mapper.ppu_read(0x0fd8), Mapped::Chr(0x2fd8));
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x1000));
+        mapper.ppu_read(0x0fef);
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x1000));
+        assert_eq!(mapper.ppu_read(0x0fe8), Mapped::Chr(0x1fe8));
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x2000));
+    }
+
+    #[test]
+    fn upper_latch_flips_on_any_row() {
+        let mut mapper = mapper();
+        assert_eq!(mapper.ppu_read(0x1fdf), Mapped::Chr(0x4fdf));
+        assert_eq!(mapper.ppu_read(0x1000), Mapped::Chr(0x3000));
+        assert_eq!(mapper.ppu_read(0x1fea), Mapped::Chr(0x3fea));
+        assert_eq!(mapper.ppu_read(0x1000), Mapped::Chr(0x4000));
+        // The halves latch independently
+        assert_eq!(mapper.ppu_read(0x0000), Mapped::Chr(0x2000));
+    }
+}
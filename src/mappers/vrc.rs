@@ -0,0 +1,172 @@
+use crate::state::InvalidState;
+use crate::state::StateReader;
+use crate::state::StateWriter;
+use crate::cartridge::Mirroring;
+
+// The prescaler counts down by 3 each CPU cycle, clocking the counter once a scanline (341 dots)
+const PRESCALER_PERIOD: i16 = 341;
+
+// IRQ counter shared by the Konami VRC boards. Counts up from the latch and
+// fires on overflow, either every CPU cycle or once per (approximate) scanline
+#[derive(Debug, Default)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+
+    // VRC4 loads the latch a nibble at a time
+    pub fn write_latch_low(&mut self, data: u8) {
+        self.latch = (self.latch & 0xf0) | (data & 0x0f);
+    }
+
+    pub fn write_latch_high(&mut self, data: u8) {
+        self.latch = (self.latch & 0x0f) | (data << 4);
+    }
+
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        self.pending = false;
+
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.cycle_mode {
+            self.clock_counter();
+        }
+        else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.pending = true;
+        }
+        else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn save_state(&self, state: StateWriter) -> StateWriter {
+        state
+            .u8(self.latch)
+            .u8(self.counter)
+            .u16(self.prescaler as u16)
+            .bool(self.enabled)
+            .bool(self.enable_after_ack)
+            .bool(self.cycle_mode)
+            .bool(self.pending)
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), InvalidState> {
+        self.latch = r.u8()?;
+        self.counter = r.u8()?;
+        self.prescaler = r.u16()? as i16;
+        self.enabled = r.bool()?;
+        self.enable_after_ack = r.bool()?;
+        self.cycle_mode = r.bool()?;
+        self.pending = r.bool()?;
+        Ok(())
+    }
+}
+
+// Fold a board's wiring of CPU lines `a0` and `a1` down to one of the four
+// registers per $1000 page, as $x000-$x003
+pub fn register(addr: u16, a0: u16, a1: u16) -> u16 {
+    let a0 = (addr & a0 != 0) as u16;
+    let a1 = (addr & a1 != 0) as u16;
+    (addr & 0xf000) | (a1 << 1) | a0
+}
+
+// The mirroring control common to VRC4, VRC6 and VRC7 (VRC2 only has bit 0)
+pub fn mirroring(control: u8) -> Mirroring {
+    match control & 0x03 {
+        0 => Mirroring::Vertical,
+        1 => Mirroring::Horizontal,
+        2 => Mirroring::SingleScreenLower,
+        _ => Mirroring::SingleScreenUpper,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock_until_pending(irq: &mut VrcIrq, limit: u32) -> Option<u32> {
+        (1..=limit).find(|_| {
+            irq.clock();
+            irq.pending()
+        })
+    }
+
+    #[test]
+    fn cycle_mode_counts_every_cpu_cycle() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xfd);
+        irq.write_control(0x06);
+        assert_eq!(clock_until_pending(&mut irq, 1000), Some(3));
+    }
+
+    #[test]
+    fn scanline_mode_counts_every_341_dots() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xfd);
+        irq.write_control(0x03);
+        // Three scanlines take 341 CPU cycles, split 114, 114 and 113 by the prescaler
+        assert_eq!(clock_until_pending(&mut irq, 1000), Some(341));
+
+        // The counter reloads from the latch and carries on once acknowledged
+        irq.acknowledge();
+        assert!(!irq.pending());
+        assert_eq!(clock_until_pending(&mut irq, 1000), Some(341));
+    }
+
+    #[test]
+    fn register_folds_the_wired_lines_to_a0_and_a1() {
+        // VRC6a (A0, A1) and VRC6b (A1, A0)
+        assert_eq!(register(0x9001, 0x01, 0x02), 0x9001);
+        assert_eq!(register(0x9001, 0x02, 0x01), 0x9002);
+        // VRC4e wires A2 and A3, the other address lines are ignored
+        assert_eq!(register(0xb00c, 0x04, 0x08), 0xb003);
+        assert_eq!(register(0xb0f3, 0x04, 0x08), 0xb000);
+    }
+}
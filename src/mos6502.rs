@@ -37,6 +37,10 @@ pub struct Cpu<'a> {
     status: u8,
     bus: Rc<RefCell<Bus>>,
     wait: u8,
+    nmi_line: bool,
+    nmi_pending: bool,
+    // Print each instruction as it's executed
+    trace: bool,
     lookup: [Op<'a>; 256],
 }
 
@@ -66,6 +70,9 @@ impl <'a> Cpu <'a> {
             status: 0,
             bus: bus,
             wait: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            trace: false,
             lookup: Self::get_op_matrix()
         }
     }
@@ -78,21 +85,48 @@ impl <'a> Cpu <'a> {
         self.clock();
     }
 
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
     pub fn clock(&mut self) {
         if self.wait > 0 {
             self.wait -= 1;
-            return;
+        }
+        // Interrupts are only taken between instructions
+        else if self.nmi_pending {
+            self.nmi_pending = false;
+            self.nmi();
+            self.wait -= 1;
+        }
+        else if self.bus.borrow().irq() && !self.get_flag(Flag::I) {
+            self.irq();
+            self.wait -= 1;
+        }
+        else {
+            //Next instruction
+            let opcode = self.read(self.pc);
+            self.pc += 1;
+
+            let op = self.lookup[opcode as usize];
+            if self.trace {
+                println!("{:#06x} {}", self.pc - 1, op.name);
+            }
+            let amr = (op.addr_mode)(self);
+            let additional_cycles = (op.op)(self, amr);
+            self.wait = op.cycles + additional_cycles - 1;
         }
 
-        //Next instruction
-        let opcode = self.read(self.pc);
-        self.pc += 1;
+        self.poll_nmi();
+    }
 
-        let op = self.lookup[opcode as usize];
-        println!("{}", op.name);
-        let amr = (op.addr_mode)(self);
-        let additional_cycles = (op.op)(self, amr);
-        self.wait = op.cycles + additional_cycles - 1;
+    // NMI is edge triggered, latch it on the line going active
+    fn poll_nmi(&mut self) {
+        let line = self.bus.borrow().nmi();
+        if line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = line;
     }
 
     pub fn reset(&mut self) {
@@ -110,6 +144,8 @@ impl <'a> Cpu <'a> {
         self.status = 0x00;
         self.set_flag(Flag::U, true);
 
+        self.nmi_line = false;
+        self.nmi_pending = false;
         self.wait = 8;
     }
 
@@ -375,7 +411,6 @@ impl <'a> Cpu <'a> {
 
     fn take_branch(&mut self, amr: AddrModeResult) -> u8 {
         let mut cycles = 1;
-        println!("{:#04x}", self.pc);
         let addr = self.get_rel_addr(amr);
         
         if addr & 0xff00 != self.pc & 0xff00 {
@@ -383,7 +418,6 @@ impl <'a> Cpu <'a> {
         }
 
         self.pc = addr;
-        println!("{:#04x}", self.pc);
 
         return cycles;
     }
@@ -1,21 +1,46 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use crate::ppu;
 use crate::ppu::Ppu;
+use crate::apu::Apu;
+use crate::apu::DMC_STALL_CYCLES;
+use crate::audio::Resampler;
 use crate::mos6502::Cpu;
 use crate::cartridge::Cartridge;
+use crate::cartridge::Mirroring;
+use crate::mappers::Mapped;
 use crate::ram::Ram;
+use crate::controller::Controller;
+use crate::dma::OamDma;
+use crate::dma::OamDmaTransfer;
 
 pub trait BusDevice {
     fn read(&mut self, addr: u16) -> Option<u8>;
     fn write(&mut self, addr: u16, data: u8);
+
+    // Interrupt lines, asserted while true
+    fn nmi(&self) -> bool {
+        false
+    }
+
+    fn irq(&self) -> bool {
+        false
+    }
 }
 
 pub struct Nes <'a> {
     bus: Rc<RefCell<Bus>>,
     cpu:  Rc<RefCell<Cpu<'a>>>,
     ppu:  Rc<RefCell<Ppu>>,
+    apu:  Rc<RefCell<Apu>>,
+    controllers: [Rc<RefCell<Controller>>; 2],
+    oam_dma: Rc<RefCell<OamDma>>,
+    oam_dma_transfer: Option<OamDmaTransfer>,
     cartridge: Option<Rc<RefCell<Cartridge>>>,
     clock_count: u64,
+    // CPU cycles left where the CPU is halted for DMA
+    cpu_stall: u32,
+    audio: Option<Resampler>,
 }
 
 
@@ -23,50 +48,177 @@ pub struct Bus {
     devices: Vec<Rc<RefCell<dyn BusDevice>>>,
 }
 
-impl Bus {
-
+// The PPU's own 14 bit address space
+// $0000-$1FFF pattern tables (cartridge)
+// $2000-$3EFF nametables (CIRAM, mapped by the cartridge)
+// $3F00-$3FFF palette RAM
+pub struct PpuBus {
+    cartridge: Option<Rc<RefCell<Cartridge>>>,
+    // 2KB of CIRAM, plus the extra 2KB a four screen board carries
+    vram: [u8; 0x1000],
+    palette: [u8; 0x20],
 }
 
 impl Nes <'_> {
     pub fn new() -> Self {
         let bus = Rc::new(RefCell::new(Bus::new()));
-        let ppu = Rc::new(RefCell::new(Ppu::new(Rc::clone(&bus))));
+        let ppu = Rc::new(RefCell::new(Ppu::new()));
         let ram = Rc::new(RefCell::new(Ram::new()));
+        let apu = Rc::new(RefCell::new(Apu::new()));
+        let controllers = [
+            Rc::new(RefCell::new(Controller::new(0))),
+            Rc::new(RefCell::new(Controller::new(1))),
+        ];
+        let oam_dma = Rc::new(RefCell::new(OamDma::new()));
 
         {
             let mut mut_bus = bus.borrow_mut();
             mut_bus.connect(ram.clone());
             mut_bus.connect(ppu.clone());
+            mut_bus.connect(apu.clone());
+            for controller in controllers.iter() {
+                mut_bus.connect(controller.clone());
+            }
+            mut_bus.connect(oam_dma.clone());
         }
 
         let cpu = Rc::new(RefCell::new(Cpu::new(Rc::clone(&bus))));
 
         Self {
-            cpu: cpu,
-            ppu: ppu,
-            bus: bus,
+            cpu,
+            ppu,
+            apu,
+            controllers,
+            oam_dma,
+            oam_dma_transfer: None,
+            bus,
             cartridge: None,
             clock_count: 0,
+            cpu_stall: 0,
+            audio: None,
         }
     }
 
-    pub fn insert(&mut self, cartridge: Cartridge) {
+    pub fn insert(&mut self, cartridge: Cartridge) -> std::io::Result<()> {
+        self.eject()?;
+
         let cartridge = Rc::new(RefCell::new(cartridge));
+        cartridge.borrow_mut().load_save()?;
         self.bus.borrow_mut().connect(cartridge.clone());
+        self.ppu.borrow_mut().insert(cartridge.clone());
         self.cartridge = Some(cartridge);
+        Ok(())
+    }
+
+    // Remove the cartridge, writing out its save first
+    pub fn eject(&mut self) -> std::io::Result<()> {
+        if let Some(cartridge) = self.cartridge.take() {
+            let device: Rc<RefCell<dyn BusDevice>> = cartridge.clone();
+            self.bus.borrow_mut().disconnect(&device);
+            self.ppu.borrow_mut().eject();
+            cartridge.borrow().write_save()?;
+        }
+        Ok(())
+    }
+
+    // Persist battery backed RAM without ejecting
+    pub fn flush_save(&mut self) -> std::io::Result<()> {
+        match &self.cartridge {
+            Some(cartridge) => cartridge.borrow().write_save(),
+            None => Ok(()),
+        }
     }
 
     pub fn clock(&mut self) {
         self.ppu.borrow_mut().clock();
         if self.clock_count % 3 == 0 {
-            self.cpu.borrow_mut().clock();
+            if let Some(transfer) = &mut self.oam_dma_transfer {
+                // The CPU is halted for the whole transfer
+                if transfer.clock(&mut self.bus.borrow_mut()) {
+                    self.oam_dma_transfer = None;
+                }
+            }
+            else if self.cpu_stall > 0 {
+                self.cpu_stall -= 1;
+            }
+            else {
+                self.cpu.borrow_mut().clock();
+
+                let request = self.oam_dma.borrow_mut().take_request();
+                if let Some(page) = request {
+                    let odd_cycle = (self.clock_count / 3) % 2 == 1;
+                    self.oam_dma_transfer = Some(OamDmaTransfer::new(page, odd_cycle));
+                }
+            }
+
+            self.apu.borrow_mut().clock();
+            self.dmc_dma();
+
+            if let Some(cartridge) = &self.cartridge {
+                cartridge.borrow_mut().cpu_clock();
+            }
+
+            if let Some(audio) = &mut self.audio {
+                let expansion = self.cartridge.as_ref().map_or(0.0, |cartridge| cartridge.borrow().audio());
+                audio.push(self.apu.borrow().sample() + expansion);
+            }
         }
         self.clock_count += 1;
     }
 
+    // Set the pressed buttons on controller port 0 or 1, see Controller::set_buttons
+    // for the bit layout. There are no other ports, so anything else is ignored
+    pub fn set_buttons(&mut self, port: usize, state: u8) {
+        if let Some(controller) = self.controllers.get(port) {
+            controller.borrow_mut().set_buttons(state);
+        }
+    }
+
+    // Run until the PPU finishes the current frame
+    pub fn run_frame(&mut self) {
+        loop {
+            self.clock();
+            if self.ppu.borrow_mut().take_frame_complete() {
+                break;
+            }
+        }
+    }
+
+    // Save the picture on screen as a PPM image
+    pub fn screenshot(&self, filename: &str) -> std::io::Result<()> {
+        ppu::write_ppm(filename, self.ppu.borrow().frame())
+    }
+
+    // Start collecting audio resampled to `sample_rate`
+    pub fn enable_audio(&mut self, sample_rate: u32) {
+        self.audio = Some(Resampler::new(sample_rate));
+    }
+
+    // Take the samples produced since the last call
+    pub fn drain_audio(&mut self) -> Vec<f32> {
+        match &mut self.audio {
+            Some(audio) => audio.drain(),
+            None => vec![],
+        }
+    }
+
+    fn dmc_dma(&mut self) {
+        let request = self.apu.borrow().dmc_dma_request();
+        if let Some(addr) = request {
+            let data = self.bus.borrow_mut().read(addr);
+            self.apu.borrow_mut().dmc_dma_complete(data);
+            self.cpu_stall += DMC_STALL_CYCLES;
+        }
+    }
+
     pub fn reset(&mut self) {
         self.cpu.borrow_mut().reset();
     }
+
+    // Log every instruction the CPU executes to stdout
+    pub fn set_trace(&mut self, trace: bool) {
+        self.cpu.borrow_mut().set_trace(trace);
+    }
 }
 
 
@@ -81,6 +233,10 @@ impl Bus {
         self.devices.push(dev);
     }
 
+    pub fn disconnect(&mut self, dev: &Rc<RefCell<dyn BusDevice>>) {
+        self.devices.retain(|d| !Rc::ptr_eq(d, dev));
+    }
+
     pub fn read(&mut self, addr: u16) -> u8 {
         self.devices.iter_mut().find_map(|dev| dev.borrow_mut().read(addr) ).unwrap_or(0)
     }
@@ -91,11 +247,201 @@ impl Bus {
         }
     }
 
-    fn ppu_read(&self, addr: u16) -> Option<u8> {
-        None
+    pub fn nmi(&self) -> bool {
+        self.devices.iter().any(|dev| dev.borrow().nmi())
+    }
+
+    pub fn irq(&self) -> bool {
+        self.devices.iter().any(|dev| dev.borrow().irq())
+    }
+}
+
+impl PpuBus {
+    pub fn new() -> Self {
+        Self {
+            cartridge: None,
+            vram: [0; 0x1000],
+            palette: [0; 0x20],
+        }
+    }
+
+    pub fn insert(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
+        self.cartridge = Some(cartridge);
     }
 
-    fn ppu_write(&mut self, addr: u16, data: u8) {
-        
+    pub fn eject(&mut self) {
+        self.cartridge = None;
     }
-}
\ No newline at end of file
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr >= 0x3f00 {
+            return self.palette[Self::palette_index(addr)];
+        }
+
+        match &self.cartridge {
+            Some(cart) => {
+                let mut cart = cart.borrow_mut();
+                cart.ppu_address(addr);
+                match cart.ppu_read(addr) {
+                    Mapped::Data(data) => data,
+                    Mapped::Ciram(a) => self.vram[a & 0x0fff],
+                    _ => 0,
+                }
+            }
+            None if addr >= 0x2000 => self.vram[Mirroring::Horizontal.ciram_offset(addr)],
+            None => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let addr = addr & 0x3fff;
+        if addr >= 0x3f00 {
+            self.palette[Self::palette_index(addr)] = data & 0x3f;
+            return;
+        }
+
+        let mapped = match &self.cartridge {
+            Some(cart) => {
+                let mut cart = cart.borrow_mut();
+                cart.ppu_address(addr);
+                cart.ppu_write(addr, data)
+            }
+            None if addr >= 0x2000 => Mapped::Ciram(Mirroring::Horizontal.ciram_offset(addr)),
+            None => Mapped::None,
+        };
+
+        if let Mapped::Ciram(a) = mapped {
+            self.vram[a & 0x0fff] = data;
+        }
+    }
+
+    // $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+    fn palette_index(addr: u16) -> usize {
+        let index = addr & 0x1f;
+        if index & 0x13 == 0x10 {
+            (index & 0x0f) as usize
+        }
+        else {
+            index as usize
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // Strobe the controllers and shift out all eight buttons on `addr`
+    fn read_buttons(nes: &Nes, addr: u16) -> u8 {
+        let mut bus = nes.bus.borrow_mut();
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        (0..8).fold(0, |state, bit| state | (bus.read(addr) & 0x01) << bit)
+    }
+
+    #[test]
+    fn set_buttons_per_port() {
+        let mut nes = Nes::new();
+        nes.set_buttons(0, 0x09);
+        nes.set_buttons(1, 0x90);
+        assert_eq!(read_buttons(&nes, 0x4016), 0x09);
+        assert_eq!(read_buttons(&nes, 0x4017), 0x90);
+    }
+
+    #[test]
+    fn set_buttons_ignores_missing_ports() {
+        let mut nes = Nes::new();
+        nes.set_buttons(2, 0xff);
+        nes.set_buttons(usize::MAX, 0xff);
+        assert_eq!(read_buttons(&nes, 0x4016), 0x00);
+        assert_eq!(read_buttons(&nes, 0x4017), 0x00);
+    }
+
+    fn cpu_cycle(nes: &mut Nes) {
+        for _ in 0..3 {
+            nes.clock();
+        }
+    }
+
+    // Start a DMA from page $02 on an even or odd CPU cycle, returning how long the CPU is halted
+    fn oam_dma_cycles(odd_cycle: bool) -> (Nes<'static>, u32) {
+        let mut nes = Nes::new();
+        if odd_cycle {
+            cpu_cycle(&mut nes);
+        }
+
+        for n in 0..0x100 {
+            nes.bus.borrow_mut().write(0x0200 + n, n as u8);
+        }
+        nes.bus.borrow_mut().write(0x4014, 0x02);
+        // The cycle that wrote $4014 completes before the CPU is halted
+        cpu_cycle(&mut nes);
+
+        let mut cycles = 0;
+        while nes.oam_dma_transfer.is_some() {
+            cpu_cycle(&mut nes);
+            cycles += 1;
+        }
+        (nes, cycles)
+    }
+
+    #[test]
+    fn oam_dma_stalls_513_cycles_from_even_cycle() {
+        assert_eq!(oam_dma_cycles(false).1, 513);
+    }
+
+    #[test]
+    fn oam_dma_stalls_514_cycles_from_odd_cycle() {
+        assert_eq!(oam_dma_cycles(true).1, 514);
+    }
+
+    #[test]
+    fn oam_dma_copies_page_to_oam() {
+        let (nes, _) = oam_dma_cycles(false);
+        let mut bus = nes.bus.borrow_mut();
+        for n in 0..0x100u16 {
+            bus.write(0x2003, n as u8);
+            // Attribute bytes don't store bits 2-4
+            let expected = if n & 0x03 == 2 { n as u8 & 0xe3 } else { n as u8 };
+            assert_eq!(bus.read(0x2004), expected, "OAM byte {}", n);
+        }
+    }
+
+    // Battery backed NROM with 8KB of PRG-RAM, saving to `path`
+    fn battery_cartridge(path: &std::path::Path) -> Cartridge {
+        let mut file = b"NES\x1a\x02\x01\x02\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        file.resize(16 + 0x8000 + 0x2000, 0);
+        let mut cartridge = Cartridge::from_bytes(&file).unwrap();
+        cartridge.set_save_path(path);
+        cartridge
+    }
+
+    #[test]
+    fn battery_ram_is_saved_on_eject_and_flush() {
+        let path = std::env::temp_dir().join(format!("nes-test-{}.sav", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut nes = Nes::new();
+        nes.insert(battery_cartridge(&path)).unwrap();
+        nes.bus.borrow_mut().write(0x6000, 0x12);
+        nes.bus.borrow_mut().write(0x7fff, 0x34);
+        nes.eject().unwrap();
+        let saved = std::fs::read(&path).unwrap();
+
+        nes.insert(battery_cartridge(&path)).unwrap();
+        let restored = {
+            let mut bus = nes.bus.borrow_mut();
+            let restored = (bus.read(0x6000), bus.read(0x7fff));
+            bus.write(0x6000, 0x56);
+            restored
+        };
+        nes.flush_save().unwrap();
+        let flushed = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved.len(), 0x2000);
+        assert_eq!(restored, (0x12, 0x34));
+        assert_eq!(flushed[0], 0x56);
+    }
+}
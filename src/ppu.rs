@@ -1,50 +1,620 @@
 use crate::nes::BusDevice;
-use crate::nes::Bus;
+use crate::nes::PpuBus;
+use crate::cartridge::Cartridge;
 use std::cell::RefCell;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
 use std::rc::Rc;
 
+enum Ctrl {
+    NametableX = (1 << 0),
+    NametableY = (1 << 1),
+    IncrementMode = (1 << 2),
+    PatternSprite = (1 << 3),
+    PatternBackground = (1 << 4),
+    SpriteSize = (1 << 5),
+    SlaveMode = (1 << 6), // Unused
+    EnableNmi = (1 << 7),
+}
+
+enum Mask {
+    Greyscale = (1 << 0),
+    ShowBackgroundLeft = (1 << 1),
+    ShowSpritesLeft = (1 << 2),
+    ShowBackground = (1 << 3),
+    ShowSprites = (1 << 4),
+    EmphasizeRed = (1 << 5),
+    EmphasizeGreen = (1 << 6),
+    EmphasizeBlue = (1 << 7),
+}
+
+enum Status {
+    SpriteOverflow = (1 << 5),
+    SpriteZeroHit = (1 << 6),
+    VerticalBlank = (1 << 7),
+}
+
+enum SpriteAttrib {
+    Palette = 0x03,
+    Priority = (1 << 5),
+    FlipHorizontal = (1 << 6),
+    FlipVertical = (1 << 7),
+}
+
+// Roughly 600ms of frames before an undriven open bus bit reads back as 0
+const OPEN_BUS_DECAY_FRAMES: u8 = 36;
+
 //#[derive(Debug)]
 pub struct Ppu {
-    memory: [u8; 0x8],
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    data_buffer: u8,
+    open_bus: u8,
+    open_bus_decay: [u8; 8],
     pal: [(u8, u8, u8); 64],
     image: [(u8, u8, u8); 256*240],
-    bus:  Rc<RefCell<Bus>>,
+    bus: PpuBus,
     scanline: i32,
     cycle: i32,
+    odd_frame: bool,
     frame_complete: bool,
+    suppress_vblank: bool,
+
+    // Loopy registers
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
+
+    // Background fetch latches
+    bg_next_tile_id: u8,
+    bg_next_tile_attrib: u8,
+    bg_next_tile_lsb: u8,
+    bg_next_tile_msb: u8,
+
+    // Background shift registers
+    bg_shifter_pattern_lo: u16,
+    bg_shifter_pattern_hi: u16,
+    bg_shifter_attrib_lo: u16,
+    bg_shifter_attrib_hi: u16,
+
+    // Sprites
+    oam: [u8; 0x100],
+    oam_addr: u8,
+    secondary_oam: [u8; 0x20],
+    sprite_count: usize,
+    sprite_zero_on_line: bool,
+    sprite_shifter_pattern_lo: [u8; 8],
+    sprite_shifter_pattern_hi: [u8; 8],
+    sprite_attrib: [u8; 8],
+    sprite_x: [u8; 8],
 }
 
 impl Ppu {
-    pub fn new(bus: Rc<RefCell<Bus>>) -> Self {
+    pub fn new() -> Self {
         Self {
-            memory: [0xff; 0x8],
-            bus: bus,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            data_buffer: 0,
+            open_bus: 0,
+            open_bus_decay: [0; 8],
+            bus: PpuBus::new(),
             cycle: 0,
-            scanline: -1, //TODO: Make this -1
+            scanline: -1,
+            odd_frame: false,
             frame_complete: false,
+            suppress_vblank: false,
             image: [(0, 0, 0); 256*240],
-            pal: Self::get_pal()
+            pal: Self::get_pal(),
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            bg_next_tile_id: 0,
+            bg_next_tile_attrib: 0,
+            bg_next_tile_lsb: 0,
+            bg_next_tile_msb: 0,
+            bg_shifter_pattern_lo: 0,
+            bg_shifter_pattern_hi: 0,
+            bg_shifter_attrib_lo: 0,
+            bg_shifter_attrib_hi: 0,
+            oam: [0; 0x100],
+            oam_addr: 0,
+            secondary_oam: [0xff; 0x20],
+            sprite_count: 0,
+            sprite_zero_on_line: false,
+            sprite_shifter_pattern_lo: [0; 8],
+            sprite_shifter_pattern_hi: [0; 8],
+            sprite_attrib: [0; 8],
+            sprite_x: [0; 8],
         }
     }
 
+    pub fn insert(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
+        self.bus.insert(cartridge);
+    }
+
+    pub fn eject(&mut self) {
+        self.bus.eject();
+    }
+
+    // The last picture drawn, 256x240 RGB from the top left
+    pub fn frame(&self) -> &[(u8, u8, u8)] {
+        &self.image
+    }
+
+    // Returns true once per frame, when the pre-render line is reached
+    pub fn take_frame_complete(&mut self) -> bool {
+        let complete = self.frame_complete;
+        self.frame_complete = false;
+        complete
+    }
+
     pub fn clock(&mut self) {
-        self.set_pixel(0x30);
+        if self.scanline == 241 && self.cycle == 1 {
+            if !self.suppress_vblank {
+                self.set_status(Status::VerticalBlank, true);
+            }
+            self.suppress_vblank = false;
+        }
+
+        if self.scanline == -1 && self.cycle == 1 {
+            self.set_status(Status::VerticalBlank, false);
+            self.set_status(Status::SpriteOverflow, false);
+            self.set_status(Status::SpriteZeroHit, false);
+        }
+
+        if self.scanline >= -1 && self.scanline < 240 {
+            self.background_fetch();
+            self.sprite_fetch();
+        }
+
+        let mut colour = 0x00;
+        if self.scanline >= 0 && self.scanline < 240 && self.cycle >= 1 && self.cycle <= 256 {
+            let (pixel, palette) = self.compose_pixel();
+            colour = self.ppu_read(0x3f00 + ((palette as u16) << 2) + pixel as u16);
+        }
+        self.set_pixel(colour);
 
         self.cycle += 1;
 
+        // The pre-render line is one dot shorter on odd frames when rendering
+        if self.scanline == -1 && self.cycle == 340 && self.odd_frame && self.rendering_enabled() {
+            self.cycle = 341;
+        }
+
         if self.cycle >= 341 {
             self.cycle = 0;
             self.scanline += 1;
             if self.scanline >= 261 {
                 self.scanline = -1;
+                self.odd_frame = !self.odd_frame;
                 self.frame_complete = true;
+                self.decay_open_bus();
+            }
+        }
+    }
+
+    fn background_fetch(&mut self) {
+        // With rendering off the PPU makes no fetches at all, which mappers watching the bus rely on
+        if !self.rendering_enabled() {
+            return;
+        }
+
+        if (2..258).contains(&self.cycle) || (321..338).contains(&self.cycle) {
+            self.update_shifters();
+
+            match (self.cycle - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.bg_next_tile_id = self.ppu_read(0x2000 | (self.v & 0x0fff));
+                }
+                2 => {
+                    let addr = 0x23c0
+                        | (self.v & 0x0c00)
+                        | ((self.coarse_y() >> 2) << 3)
+                        | (self.coarse_x() >> 2);
+                    let mut attrib = self.ppu_read(addr);
+                    if self.coarse_y() & 0x02 != 0 {
+                        attrib >>= 4;
+                    }
+                    if self.coarse_x() & 0x02 != 0 {
+                        attrib >>= 2;
+                    }
+                    self.bg_next_tile_attrib = attrib & 0x03;
+                }
+                4 => {
+                    let addr = self.background_pattern_addr();
+                    self.bg_next_tile_lsb = self.ppu_read(addr);
+                }
+                6 => {
+                    let addr = self.background_pattern_addr() + 8;
+                    self.bg_next_tile_msb = self.ppu_read(addr);
+                }
+                7 => self.increment_scroll_x(),
+                _ => {}
+            }
+        }
+
+        if self.cycle == 256 {
+            self.increment_scroll_y();
+        }
+
+        if self.cycle == 257 {
+            self.load_background_shifters();
+            self.transfer_address_x();
+        }
+
+        // Unused nametable fetches at the end of the line
+        if self.cycle == 338 || self.cycle == 340 {
+            self.bg_next_tile_id = self.ppu_read(0x2000 | (self.v & 0x0fff));
+        }
+
+        if self.scanline == -1 && self.cycle >= 280 && self.cycle < 305 {
+            self.transfer_address_y();
+        }
+    }
+
+    fn sprite_fetch(&mut self) {
+        if (2..258).contains(&self.cycle) {
+            self.update_sprite_shifters();
+        }
+
+        if self.cycle == 257 {
+            self.evaluate_sprites();
+        }
+
+        // Each of the eight sprite slots gets two pattern fetches between dots 257 and 320
+        if (257..321).contains(&self.cycle) && self.rendering_enabled() {
+            let slot = ((self.cycle - 257) / 8) as usize;
+            match (self.cycle - 257) % 8 {
+                4 => {
+                    let addr = self.sprite_pattern_addr(slot);
+                    let mut lo = self.ppu_read(addr);
+                    if self.sprite_attrib[slot] & SpriteAttrib::FlipHorizontal as u8 != 0 {
+                        lo = lo.reverse_bits();
+                    }
+                    self.sprite_shifter_pattern_lo[slot] = if slot < self.sprite_count { lo } else { 0 };
+                }
+                6 => {
+                    let addr = self.sprite_pattern_addr(slot) + 8;
+                    let mut hi = self.ppu_read(addr);
+                    if self.sprite_attrib[slot] & SpriteAttrib::FlipHorizontal as u8 != 0 {
+                        hi = hi.reverse_bits();
+                    }
+                    self.sprite_shifter_pattern_hi[slot] = if slot < self.sprite_count { hi } else { 0 };
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Fill secondary OAM with the sprites that land on the next scanline
+    fn evaluate_sprites(&mut self) {
+        self.secondary_oam = [0xff; 0x20];
+        self.sprite_count = 0;
+        self.sprite_zero_on_line = false;
+
+        if self.scanline < 0 || !self.rendering_enabled() {
+            self.load_sprite_slots();
+            return;
+        }
+
+        let height = self.sprite_height();
+        for n in 0..64 {
+            let entry = n * 4;
+            let diff = self.scanline - self.oam[entry] as i32;
+            if diff < 0 || diff >= height {
+                continue;
             }
+
+            if self.sprite_count == 8 {
+                self.set_status(Status::SpriteOverflow, true);
+                break;
+            }
+
+            if n == 0 {
+                self.sprite_zero_on_line = true;
+            }
+            let slot = self.sprite_count * 4;
+            self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[entry..entry + 4]);
+            self.sprite_count += 1;
+        }
+
+        self.load_sprite_slots();
+    }
+
+    fn load_sprite_slots(&mut self) {
+        for slot in 0..8 {
+            self.sprite_attrib[slot] = self.secondary_oam[slot * 4 + 2];
+            self.sprite_x[slot] = self.secondary_oam[slot * 4 + 3];
+        }
+    }
+
+    fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+        let y = self.secondary_oam[slot * 4];
+        let tile = self.secondary_oam[slot * 4 + 1];
+        let attrib = self.sprite_attrib[slot];
+
+        // Empty slots still fetch tile $FF, row 0
+        let mut row = if slot < self.sprite_count {
+            (self.scanline - y as i32) as u16
+        }
+        else {
+            0
+        };
+
+        if attrib & SpriteAttrib::FlipVertical as u8 != 0 && slot < self.sprite_count {
+            row = self.sprite_height() as u16 - 1 - row;
+        }
+
+        if self.get_ctrl(Ctrl::SpriteSize) {
+            // 8x16: bit 0 of the tile picks the table, rows 8-15 come from the next tile
+            let table = (tile as u16 & 0x01) << 12;
+            let tile = (tile & 0xfe) as u16 + (row >> 3);
+            table | (tile << 4) | (row & 0x07)
+        }
+        else {
+            let table = if self.get_ctrl(Ctrl::PatternSprite) { 0x1000 } else { 0x0000 };
+            table | ((tile as u16) << 4) | (row & 0x07)
+        }
+    }
+
+    fn sprite_height(&self) -> i32 {
+        if self.get_ctrl(Ctrl::SpriteSize) { 16 } else { 8 }
+    }
+
+    fn update_sprite_shifters(&mut self) {
+        if !self.get_mask(Mask::ShowSprites) {
+            return;
+        }
+
+        for slot in 0..self.sprite_count {
+            if self.sprite_x[slot] > 0 {
+                self.sprite_x[slot] -= 1;
+            }
+            else {
+                self.sprite_shifter_pattern_lo[slot] <<= 1;
+                self.sprite_shifter_pattern_hi[slot] <<= 1;
+            }
+        }
+    }
+
+    // Returns the first opaque sprite pixel as (pixel, palette, priority, is sprite zero)
+    fn sprite_pixel(&self) -> (u8, u8, bool, bool) {
+        if !self.get_mask(Mask::ShowSprites) {
+            return (0, 0, false, false);
+        }
+        if self.cycle <= 8 && !self.get_mask(Mask::ShowSpritesLeft) {
+            return (0, 0, false, false);
+        }
+
+        for slot in 0..self.sprite_count {
+            if self.sprite_x[slot] != 0 {
+                continue;
+            }
+
+            let p0 = (self.sprite_shifter_pattern_lo[slot] & 0x80 != 0) as u8;
+            let p1 = (self.sprite_shifter_pattern_hi[slot] & 0x80 != 0) as u8;
+            let pixel = (p1 << 1) | p0;
+            if pixel == 0 {
+                continue;
+            }
+
+            let attrib = self.sprite_attrib[slot];
+            let palette = (attrib & SpriteAttrib::Palette as u8) + 0x04;
+            let priority = attrib & SpriteAttrib::Priority as u8 == 0;
+            return (pixel, palette, priority, slot == 0 && self.sprite_zero_on_line);
+        }
+
+        (0, 0, false, false)
+    }
+
+    // Combine background and sprite pixels, returning (pixel, palette)
+    fn compose_pixel(&mut self) -> (u8, u8) {
+        let (bg_pixel, bg_palette) = self.background_pixel();
+        let (fg_pixel, fg_palette, fg_priority, sprite_zero) = self.sprite_pixel();
+
+        if bg_pixel != 0 && fg_pixel != 0 && sprite_zero && self.cycle != 256 {
+            self.set_status(Status::SpriteZeroHit, true);
+        }
+
+        match (bg_pixel, fg_pixel) {
+            (0, 0) => (0, 0),
+            (0, _) => (fg_pixel, fg_palette),
+            (_, 0) => (bg_pixel, bg_palette),
+            _ if fg_priority => (fg_pixel, fg_palette),
+            _ => (bg_pixel, bg_palette),
+        }
+    }
+
+    fn background_pixel(&self) -> (u8, u8) {
+        if !self.get_mask(Mask::ShowBackground) {
+            return (0, 0);
+        }
+        if self.cycle <= 8 && !self.get_mask(Mask::ShowBackgroundLeft) {
+            return (0, 0);
+        }
+
+        let mux = 0x8000 >> self.fine_x;
+
+        let p0 = (self.bg_shifter_pattern_lo & mux != 0) as u8;
+        let p1 = (self.bg_shifter_pattern_hi & mux != 0) as u8;
+        let a0 = (self.bg_shifter_attrib_lo & mux != 0) as u8;
+        let a1 = (self.bg_shifter_attrib_hi & mux != 0) as u8;
+
+        let pixel = (p1 << 1) | p0;
+        if pixel == 0 {
+            return (0, 0);
+        }
+        (pixel, (a1 << 1) | a0)
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        let table = if self.get_ctrl(Ctrl::PatternBackground) { 0x1000 } else { 0x0000 };
+        table + ((self.bg_next_tile_id as u16) << 4) + self.fine_y()
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.bg_shifter_pattern_lo = (self.bg_shifter_pattern_lo & 0xff00) | self.bg_next_tile_lsb as u16;
+        self.bg_shifter_pattern_hi = (self.bg_shifter_pattern_hi & 0xff00) | self.bg_next_tile_msb as u16;
+
+        let attrib_lo = if self.bg_next_tile_attrib & 0x01 != 0 { 0xff } else { 0x00 };
+        let attrib_hi = if self.bg_next_tile_attrib & 0x02 != 0 { 0xff } else { 0x00 };
+        self.bg_shifter_attrib_lo = (self.bg_shifter_attrib_lo & 0xff00) | attrib_lo;
+        self.bg_shifter_attrib_hi = (self.bg_shifter_attrib_hi & 0xff00) | attrib_hi;
+    }
+
+    fn update_shifters(&mut self) {
+        if self.get_mask(Mask::ShowBackground) {
+            self.bg_shifter_pattern_lo <<= 1;
+            self.bg_shifter_pattern_hi <<= 1;
+            self.bg_shifter_attrib_lo <<= 1;
+            self.bg_shifter_attrib_hi <<= 1;
         }
     }
 
+    // v register layout: yyy NN YYYYY XXXXX
+    fn coarse_x(&self) -> u16 {
+        self.v & 0x001f
+    }
+
+    fn coarse_y(&self) -> u16 {
+        (self.v >> 5) & 0x001f
+    }
+
+    fn fine_y(&self) -> u16 {
+        (self.v >> 12) & 0x0007
+    }
+
+    fn increment_scroll_x(&mut self) {
+        if !self.rendering_enabled() {
+            return;
+        }
+
+        if self.coarse_x() == 31 {
+            self.v &= !0x001f;
+            self.v ^= 0x0400; // Switch horizontal nametable
+        }
+        else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_scroll_y(&mut self) {
+        if !self.rendering_enabled() {
+            return;
+        }
+
+        if self.fine_y() < 7 {
+            self.v += 0x1000;
+            return;
+        }
+
+        self.v &= !0x7000;
+        let mut y = self.coarse_y();
+        if y == 29 {
+            y = 0;
+            self.v ^= 0x0800; // Switch vertical nametable
+        }
+        else if y == 31 {
+            y = 0;
+        }
+        else {
+            y += 1;
+        }
+        self.v = (self.v & !0x03e0) | (y << 5);
+    }
+
+    fn transfer_address_x(&mut self) {
+        if self.rendering_enabled() {
+            self.v = (self.v & !0x041f) | (self.t & 0x041f);
+        }
+    }
+
+    fn transfer_address_y(&mut self) {
+        if self.rendering_enabled() {
+            self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.get_mask(Mask::ShowBackground) || self.get_mask(Mask::ShowSprites)
+    }
+
+    fn get_ctrl(&self, f: Ctrl) -> bool {
+        self.ctrl & f as u8 != 0
+    }
+
+    fn get_mask(&self, f: Mask) -> bool {
+        self.mask & f as u8 != 0
+    }
+
+    fn set_status(&mut self, f: Status, value: bool) {
+        if value {
+            self.status |= f as u8;
+        }
+        else {
+            self.status &= !(f as u8);
+        }
+    }
+
+    // Drive the bits selected by `bits` onto the PPU's open bus latch
+    fn refresh_open_bus(&mut self, data: u8, bits: u8) {
+        self.open_bus = (self.open_bus & !bits) | (data & bits);
+        for bit in 0..8 {
+            if bits & (1 << bit) != 0 {
+                self.open_bus_decay[bit] = OPEN_BUS_DECAY_FRAMES;
+            }
+        }
+    }
+
+    fn decay_open_bus(&mut self) {
+        for bit in 0..8 {
+            if self.open_bus_decay[bit] > 0 {
+                self.open_bus_decay[bit] -= 1;
+                if self.open_bus_decay[bit] == 0 {
+                    self.open_bus &= !(1 << bit);
+                }
+            }
+        }
+    }
+
+    fn increment_vram_addr(&mut self) {
+        if self.scanline < 240 && self.rendering_enabled() {
+            // Accessing PPUDATA while rendering bumps both scroll counters
+            self.increment_scroll_x();
+            self.increment_scroll_y();
+        }
+        else if self.get_ctrl(Ctrl::IncrementMode) {
+            self.v = (self.v + 32) & 0x7fff;
+        }
+        else {
+            self.v = (self.v + 1) & 0x7fff;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let data = self.bus.read(addr);
+        if addr & 0x3fff >= 0x3f00 && self.get_mask(Mask::Greyscale) {
+            data & 0x30
+        }
+        else {
+            data
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.bus.write(addr, data);
+    }
+
     fn set_pixel(&mut self, colour: u8) {
-        if self.scanline >= 0 && self.cycle > 0 {
-            self.image[(self.cycle * 256 + self.scanline) as usize] = self.pal[colour as usize];
+        if (0..240).contains(&self.scanline) && (1..=256).contains(&self.cycle) {
+            let index = self.scanline * 256 + self.cycle - 1;
+            self.image[index as usize] = self.pal[(colour & 0x3f) as usize];
         }
     }
 
@@ -131,17 +701,275 @@ impl BusDevice for Ppu {
     // PPU data = 7
 
     fn read(&mut self, addr: u16) -> Option<u8> {
-        if (0x2000..0x4000).contains(&addr) {
-            Some(self.memory[(addr as usize) & 0x7])
-        }
-        else {
-            None
+        if !(0x2000..0x4000).contains(&addr) {
+            return None;
         }
+
+        let data = match addr & 0x7 {
+            2 => {
+                // Reading just before vblank starts means the flag (and NMI) never happen
+                if self.scanline == 241 && self.cycle == 1 {
+                    self.suppress_vblank = true;
+                }
+
+                let data = (self.status & 0xe0) | (self.open_bus & 0x1f);
+                self.refresh_open_bus(data, 0xe0);
+                self.set_status(Status::VerticalBlank, false);
+                self.w = false;
+                data
+            }
+            4 => {
+                let data = self.oam[self.oam_addr as usize];
+                self.refresh_open_bus(data, 0xff);
+                data
+            }
+            7 => {
+                let addr = self.v & 0x3fff;
+                let data = if addr >= 0x3f00 {
+                    // Palette reads bypass the buffer, which picks up the nametable underneath
+                    let colour = self.ppu_read(addr);
+                    self.data_buffer = self.ppu_read(addr - 0x1000);
+                    let data = (colour & 0x3f) | (self.open_bus & 0xc0);
+                    self.refresh_open_bus(data, 0x3f);
+                    data
+                }
+                else {
+                    let data = self.data_buffer;
+                    self.data_buffer = self.ppu_read(addr);
+                    self.refresh_open_bus(data, 0xff);
+                    data
+                };
+                self.increment_vram_addr();
+                data
+            }
+            // Write only registers return whatever is left on the bus
+            _ => self.open_bus,
+        };
+
+        Some(data)
     }
 
     fn write(&mut self, addr: u16, data: u8) {
-        if (0x2000..0x4000).contains(&addr) {
-            self.memory[(addr as usize) & 0x7] = data;
+        if !(0x2000..0x4000).contains(&addr) {
+            return;
+        }
+
+        self.refresh_open_bus(data, 0xff);
+
+        match addr & 0x7 {
+            0 => {
+                self.ctrl = data;
+                self.t = (self.t & !0x0c00) | (((data & 0x03) as u16) << 10);
+            }
+            1 => self.mask = data,
+            3 => self.oam_addr = data,
+            4 => {
+                // Bits 2-4 of the attribute byte do not exist
+                let data = if self.oam_addr & 0x03 == 2 { data & 0xe3 } else { data };
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            5 => {
+                if !self.w {
+                    self.fine_x = data & 0x07;
+                    self.t = (self.t & !0x001f) | (data >> 3) as u16;
+                }
+                else {
+                    self.t = (self.t & !0x73e0)
+                        | (((data & 0x07) as u16) << 12)
+                        | (((data >> 3) as u16) << 5);
+                }
+                self.w = !self.w;
+            }
+            6 => {
+                if !self.w {
+                    self.t = (self.t & 0x00ff) | (((data & 0x3f) as u16) << 8);
+                }
+                else {
+                    self.t = (self.t & 0xff00) | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            7 => {
+                self.ppu_write(self.v, data);
+                self.increment_vram_addr();
+            }
+            _ => {}
+        }
+    }
+
+    fn nmi(&self) -> bool {
+        self.get_ctrl(Ctrl::EnableNmi) && self.status & Status::VerticalBlank as u8 != 0
+    }
+}
+
+// Write a frame out as a binary PPM
+pub fn write_ppm(filename: &str, frame: &[(u8, u8, u8)]) -> std::io::Result<()> {
+    let mut f = BufWriter::new(File::create(filename)?);
+    f.write_all(b"P6\n256 240\n255\n")?;
+    for (r, g, b) in frame {
+        f.write_all(&[*r, *g, *b])?;
+    }
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock_to(ppu: &mut Ppu, scanline: i32, cycle: i32) {
+        while (ppu.scanline, ppu.cycle) != (scanline, cycle) {
+            ppu.clock();
+        }
+    }
+
+    fn set_address(ppu: &mut Ppu, addr: u16) {
+        ppu.write(0x2006, (addr >> 8) as u8);
+        ppu.write(0x2006, addr as u8);
+    }
+
+    #[test]
+    fn vblank_sets_and_status_read_clears_it() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, 0x80);
+        clock_to(&mut ppu, 241, 2);
+        assert!(ppu.nmi());
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x80, 0x80);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x80, 0x00);
+        assert!(!ppu.nmi());
+
+        // Cleared at the start of the pre-render line if it wasn't read
+        clock_to(&mut ppu, 241, 2);
+        clock_to(&mut ppu, -1, 2);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x80, 0x00);
+    }
+
+    #[test]
+    fn status_read_on_the_vblank_dot_suppresses_it() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, 0x80);
+        clock_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x80, 0x00);
+        ppu.clock();
+        assert!(!ppu.nmi());
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x80, 0x00);
+
+        // Only for that frame
+        clock_to(&mut ppu, 241, 1);
+        ppu.clock();
+        assert!(ppu.nmi());
+    }
+
+    #[test]
+    fn sprite_palette_backdrops_mirror_the_background() {
+        let mut ppu = Ppu::new();
+        for (n, addr) in [0x3f10, 0x3f14, 0x3f18, 0x3f1c].iter().enumerate() {
+            set_address(&mut ppu, *addr);
+            ppu.write(0x2007, 0x20 + n as u8);
+            set_address(&mut ppu, *addr - 0x10);
+            assert_eq!(ppu.read(0x2007), Some(0x20 + n as u8), "{:#06x}", addr);
+        }
+
+        // Both ways round
+        set_address(&mut ppu, 0x3f04);
+        ppu.write(0x2007, 0x15);
+        set_address(&mut ppu, 0x3f14);
+        assert_eq!(ppu.read(0x2007), Some(0x15));
+
+        // The other sprite colours are their own
+        set_address(&mut ppu, 0x3f11);
+        ppu.write(0x2007, 0x2a);
+        set_address(&mut ppu, 0x3f01);
+        assert_eq!(ppu.read(0x2007), Some(0x00));
+        // And the palette repeats every 32 bytes up to $3FFF, under two bits of open bus
+        set_address(&mut ppu, 0x3ff1);
+        assert_eq!(ppu.read(0x2007).map(|data| data & 0x3f), Some(0x2a));
+    }
+
+    #[test]
+    fn scroll_and_address_writes_fill_t_and_v() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, 0x03);
+        assert_eq!(ppu.t, 0x0c00);
+
+        // Coarse X 15, fine X 5, then coarse Y 11, fine Y 6
+        ppu.write(0x2005, 0x7d);
+        assert_eq!((ppu.t, ppu.fine_x, ppu.w), (0x0c0f, 5, true));
+        ppu.write(0x2005, 0x5e);
+        assert_eq!((ppu.t, ppu.w), (0x6d6f, false));
+        assert_eq!(ppu.v, 0x0000);
+
+        // The high byte write clears bit 14, the low byte write copies t into v
+        ppu.write(0x2006, 0xfd);
+        assert_eq!((ppu.t, ppu.w), (0x3d6f, true));
+        assert_eq!(ppu.v, 0x0000);
+        ppu.write(0x2006, 0xf0);
+        assert_eq!((ppu.t, ppu.v, ppu.w), (0x3df0, 0x3df0, false));
+    }
+
+    #[test]
+    fn status_read_resets_the_write_toggle() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2006, 0x21);
+        ppu.read(0x2002);
+        ppu.write(0x2006, 0x23);
+        ppu.write(0x2006, 0x45);
+        assert_eq!(ppu.v, 0x2345);
+
+        ppu.write(0x2005, 0x08);
+        ppu.read(0x2002);
+        ppu.write(0x2005, 0x10);
+        assert_eq!(ppu.t & 0x001f, 0x02);
+    }
+
+    // NROM with CHR-RAM: tile 1 solid, the whole nametable `background`,
+    // sprite 0 is tile 1 at 100, 30
+    fn sprite_zero_ppu(background: u8) -> Ppu {
+        let mut file = b"NES\x1a\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        file.resize(16 + 0x4000, 0);
+        let mut ppu = Ppu::new();
+        ppu.insert(Rc::new(RefCell::new(Cartridge::from_bytes(&file).unwrap())));
+
+        set_address(&mut ppu, 0x0010);
+        for _ in 0..16 {
+            ppu.write(0x2007, 0xff);
+        }
+        set_address(&mut ppu, 0x2000);
+        for _ in 0..0x3c0 {
+            ppu.write(0x2007, background);
+        }
+
+        ppu.write(0x2003, 0x00);
+        for &data in [30, 0x01, 0x00, 100].iter() {
+            ppu.write(0x2004, data);
         }
+
+        set_address(&mut ppu, 0x0000);
+        ppu.write(0x2001, 0x1e);
+        ppu
+    }
+
+    #[test]
+    fn sprite_zero_hit_over_opaque_background() {
+        let mut ppu = sprite_zero_ppu(0x01);
+        // The sprite is drawn a line below its Y, from dot 101
+        clock_to(&mut ppu, 31, 100);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x40, 0x00);
+        clock_to(&mut ppu, 31, 110);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x40, 0x40);
+
+        // Reading doesn't clear it, the pre-render line does
+        clock_to(&mut ppu, 260, 0);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x40, 0x40);
+        clock_to(&mut ppu, -1, 2);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x40, 0x00);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn no_sprite_zero_hit_over_transparent_background() {
+        let mut ppu = sprite_zero_ppu(0x00);
+        clock_to(&mut ppu, 240, 0);
+        assert_eq!(ppu.read(0x2002).unwrap() & 0x40, 0x00);
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub struct InvalidState;
+
+impl fmt::Display for InvalidState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid or truncated save state")
+    }
+}
+
+impl std::error::Error for InvalidState {}
+
+// Little endian serialisation helpers for save states
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self {
+            data: vec![],
+        }
+    }
+
+    pub fn u8(mut self, value: u8) -> Self {
+        self.data.push(value);
+        self
+    }
+
+    pub fn bool(self, value: bool) -> Self {
+        self.u8(value as u8)
+    }
+
+    pub fn u16(mut self, value: u16) -> Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn f64(self, value: f64) -> Self {
+        self.u64(value.to_bits())
+    }
+
+    pub fn bytes(mut self, value: &[u8]) -> Self {
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], InvalidState> {
+        if self.data.len() < len {
+            return Err(InvalidState);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, InvalidState> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, InvalidState> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, InvalidState> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, InvalidState> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, InvalidState> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, InvalidState> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], InvalidState> {
+        let mut a = [0; N];
+        a.copy_from_slice(self.bytes(N)?);
+        Ok(a)
+    }
+
+    // Everything should have been read, anything left over means the state doesn't match
+    pub fn finish(self) -> Result<(), InvalidState> {
+        if self.data.is_empty() { Ok(()) } else { Err(InvalidState) }
+    }
+}